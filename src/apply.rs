@@ -0,0 +1,1271 @@
+//! Applying a derfile, that is rendering all its templates for the current host and writing them
+//! to their apply paths.
+//!
+//! Templates, whose `hostnames` don't match the current host, are skipped as a whole, including
+//! all the files of template directories. Everything that happened is collected in a `Summary`,
+//! which is printed at the end of the run.
+//!
+//! Templates with `deploy = symlink` put a symlink at their target instead. It points to the
+//! template itself if it renders to itself, and otherwise to the rendered template, which is
+//! written to the build directory in der's state directory.
+//!
+//! Every written target is recorded in the manifest, so `der status` can later compare it with
+//! what der wrote and what its template renders to now.
+//!
+//! A transactional run renders every template and checks that every target can be written before
+//! writing anything, and puts back all files it already wrote if a later write fails. That
+//! includes the permissions of the targets, the rendered files in the build directory and the
+//! copies of what was applied, which are kept as the base of merges.
+//!
+//! Copied files, which were changed since der last wrote them, aren't overwritten silently. Their
+//! diff is shown and the user is asked whether to overwrite them, keep them, or merge the local
+//! changes with the new rendering. Without a terminal to ask on, local changes are kept, unless
+//! the run is forced.
+//!
+//! Hooks of the derfile run before and after the whole run, and the hooks of each template before
+//! anything is written and after all of its files were written. `on_change` hooks only run if a
+//! file was new or changed, `on_error` hooks when something fails. Dry runs don't run any hooks.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{self, Run};
+use crate::derfile::{Deploy, Derfile, Hooks, Template};
+use crate::diff::{merge, unified};
+use crate::error::*;
+use crate::manifest::{self, Entry, Manifest, Status};
+use crate::pattern::match_hostname;
+use crate::template::{
+    recursive_build, FilePermissions, ParsedTemplate, TemplateDirectory, TemplateFile,
+    TemplateStructure,
+};
+use crate::utils::{
+    ask, debug, execute_hook, hostname, is_symlink, mirror_path, remove_stale_temp_file,
+    set_owner_and_mode, state_dir, symlink_atomic, temp_file_path, write_atomic, write_if_changed,
+};
+
+/// Options of a single run of `der -a`.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Apply templates even if the current host is not in their `hostnames`.
+    pub ignore_hosts: bool,
+    /// Only show what would change, don't write anything.
+    pub dry_run: bool,
+    /// How many runs are kept in the backup store, no backups are made if 0.
+    pub keep_backups: usize,
+    /// Write either all files or none of them.
+    pub transactional: bool,
+    /// Overwrite files, which were changed since der wrote them, without asking.
+    pub force: bool,
+}
+
+/// How applying a template file changes its target file. Unchanged targets aren't written at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// The target file doesn't exist yet.
+    New,
+    /// The target file exists with different contents.
+    Changed,
+    /// The target file already has the rendered contents.
+    Unchanged,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::New => write!(f, "\x1b[32mnew\x1b[0m"),
+            Self::Changed => write!(f, "\x1b[33mchanged\x1b[0m"),
+            Self::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+/// Names of templates, which were skipped, with the reason why.
+pub type Skipped = Vec<(String, String)>;
+
+/// What happened to the templates of a derfile during a run.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    /// Whether nothing was actually written.
+    pub dry_run: bool,
+    /// Paths of all rendered files and how they were changed.
+    pub applied: Vec<(String, Change)>,
+    pub skipped: Skipped,
+    /// Paths of files, which were changed since der wrote them, and were kept that way.
+    pub kept: Vec<String>,
+    /// Identifier of the backup of all replaced files, if any were replaced.
+    pub backup: Option<String>,
+}
+
+impl Summary {
+    /// Paths of all new and changed files, which are the only ones written.
+    pub fn changed(&self) -> Vec<&str> {
+        self.applied
+            .iter()
+            .filter(|(_, change)| *change != Change::Unchanged)
+            .map(|(target, _)| target.as_str())
+            .collect()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |change: Change| {
+            self.applied
+                .iter()
+                .filter(|(_, each)| *each == change)
+                .count()
+        };
+        write!(
+            f,
+            "[\x1b[32mINFO\x1b[0m] {} {} file(s): {} {} ({} new, {} changed), {} unchanged, skipped {} template(s).",
+            if self.dry_run { "Would apply" } else { "Applied" },
+            self.applied.len(),
+            if self.dry_run { "would write" } else { "wrote" },
+            count(Change::New) + count(Change::Changed),
+            count(Change::New),
+            count(Change::Changed),
+            count(Change::Unchanged),
+            self.skipped.len()
+        )?;
+        for (name, reason) in &self.skipped {
+            write!(f, "\n    skipped {name}: {reason}")?;
+        }
+        if !self.kept.is_empty() {
+            write!(
+                f,
+                "\n[\x1b[33mWARN\x1b[0m] Kept {} locally modified file(s), overwrite them with --force:",
+                self.kept.len()
+            )?;
+            for each in &self.kept {
+                write!(f, "\n    {each}")?;
+            }
+        }
+        if let Some(backup) = &self.backup {
+            write!(
+                f,
+                "\n[\x1b[32mINFO\x1b[0m] Replaced files were backed up, restore them with: der --restore {backup}"
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A rendered template, which is ready to be put at its target.
+struct Pending {
+    /// Path of the template file or directory.
+    template: String,
+    target: PathBuf,
+    output: Output,
+    /// Contents of the target before applying, `None` if it doesn't exist.
+    current: Option<Vec<u8>>,
+    change: Change,
+    permissions: FilePermissions,
+    /// Hash of the rendered template, `None` for linked directories.
+    hash: Option<u64>,
+    /// Whether the target was changed since der wrote it.
+    modified: bool,
+    /// The rendered template merged with the local changes of the target, which is written
+    /// instead of the rendered template.
+    merged: Option<String>,
+}
+
+/// Suffix of the file next to a target, to which a merge with conflicts is written.
+const MERGE_SUFFIX: &str = ".der-merge";
+
+/// What a template puts at its target.
+enum Output {
+    /// The rendered template file.
+    File(Box<TemplateFile>, ParsedTemplate),
+    /// A symlink to `source`, which is either the template itself, or the file in the build
+    /// directory, to which the template is `rendered`.
+    Link {
+        source: PathBuf,
+        rendered: Option<ParsedTemplate>,
+    },
+}
+
+/// What was at a target before it was written.
+enum Previous {
+    Contents(Vec<u8>),
+    Link(PathBuf),
+}
+
+/// A file written during a run and what it was before, so it can be rolled back.
+struct Written {
+    target: PathBuf,
+    previous: Option<Previous>,
+    /// Whether a symlink was put at the target.
+    linked: bool,
+    /// The mode, owner and group of the previous contents.
+    permissions: Option<(u32, u32, u32)>,
+}
+
+impl Written {
+    /// Remember what's at a file, before it's written.
+    fn before(target: &Path, previous: Option<Previous>, linked: bool) -> Self {
+        let permissions = match previous {
+            Some(Previous::Contents(_)) => fs::metadata(target)
+                .ok()
+                .map(|metadata| (metadata.mode(), metadata.uid(), metadata.gid())),
+            _ => None,
+        };
+
+        Written {
+            target: target.to_path_buf(),
+            previous,
+            linked,
+            permissions,
+        }
+    }
+
+    /// Remember what's at a file, which is never a link, before it's written.
+    fn file(path: &Path) -> Self {
+        Written::before(path, fs::read(path).ok().map(Previous::Contents), false)
+    }
+
+    /// Put back what was at the file. Returns whether anything had to be changed.
+    fn undo(&self) -> Result<bool> {
+        let target = &self.target;
+        let previous = match &self.previous {
+            Some(Previous::Link(source)) => {
+                if fs::read_link(target).ok().as_ref() == Some(source) {
+                    return Ok(false);
+                }
+                symlink_atomic(source, target)?;
+                return Ok(true);
+            }
+            Some(Previous::Contents(previous)) => previous,
+            None if fs::symlink_metadata(target).is_ok() => {
+                fs::remove_file(target)?;
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+
+        let mut changed = false;
+        if self.linked && is_symlink(target) {
+            // Don't write the previous contents through the new link.
+            fs::remove_file(target)?;
+            changed = true;
+        }
+        changed |= write_if_changed(target, previous, self.permissions.map(|(mode, ..)| mode))?;
+        if let Some((mode, uid, gid)) = self.permissions {
+            changed |= set_owner_and_mode(target, Some(uid), Some(gid), Some(mode))?;
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Check if a template is meant for a host. Templates without any hostnames are meant for every
+/// host.
+pub fn is_for_host(template: &Template, hostname: &str) -> Result<bool> {
+    Ok(template.hostnames.is_empty() || match_hostname(&template.hostnames, hostname)?.is_some())
+}
+
+/// Render and write all templates of a derfile, which are meant for the current host, and run
+/// the hooks of the derfile around it.
+pub fn apply(derfile: &Derfile, options: &ApplyOptions) -> Result<Summary> {
+    if options.dry_run {
+        return apply_templates(derfile, options);
+    }
+
+    let name = derfile.path.to_string_lossy();
+    let mut vars = vec![("DER_DERFILE", name.to_string())];
+    let result = run_hook(&derfile.hooks, "before_apply", &name, &vars)
+        .and_then(|_| apply_templates(derfile, options))
+        .and_then(|summary| {
+            let changed = summary.changed();
+            vars.push(("DER_CHANGED", changed.join("\n")));
+            if !changed.is_empty() {
+                run_hook(&derfile.hooks, "on_change", &name, &vars)?;
+            }
+            run_hook(&derfile.hooks, "after_apply", &name, &vars)?;
+            Ok(summary)
+        });
+
+    result.map_err(|e| on_error(&derfile.hooks, &name, vars, e))
+}
+
+/// Render and write all templates, which are meant for the current host, each one between its
+/// `before_apply` and `after_apply` hooks.
+fn apply_templates(derfile: &Derfile, options: &ApplyOptions) -> Result<Summary> {
+    let (templates, skipped) = templates_for_host(derfile, options)?;
+    let mut summary = Summary {
+        dry_run: options.dry_run,
+        skipped,
+        ..Default::default()
+    };
+
+    let backups = backups_root(options)?;
+    let mut run = backups.as_ref().map(|root| Run::new(root));
+    let manifest_path = Manifest::path()?;
+    let mut manifest = Manifest::load(&manifest_path)?;
+
+    // Every template file with the index of the template it belongs to.
+    let mut structures = Vec::new();
+    for (ii, template) in templates.iter().enumerate() {
+        let vars = hook_vars(derfile, template, &[]);
+        if !options.dry_run {
+            run_hook(&template.hooks, "before_apply", &template.name, &vars)
+                .map_err(|e| on_error(&template.hooks, &template.name, vars.clone(), e))?;
+        }
+        let built = build(derfile, vec![template.clone()])
+            .map_err(|e| on_error(&template.hooks, &template.name, vars, e))?;
+        structures.extend(built.into_iter().map(|structure| (ii, structure)));
+    }
+    if !options.dry_run {
+        let targets = structures
+            .iter()
+            .map(|(_, structure)| output_path(structure));
+        remove_stale_temp_files(manifest.entries.keys().cloned().chain(targets))?;
+    }
+    let failed = |ii: usize, e: Error| -> Error {
+        let template = &templates[ii];
+        on_error(
+            &template.hooks,
+            &template.name,
+            hook_vars(derfile, template, &[]),
+            e,
+        )
+    };
+
+    // Targets, which were changed, of every template.
+    let mut changed = vec![Vec::new(); templates.len()];
+    if options.transactional && !options.dry_run {
+        let mut pending = Vec::new();
+        for (ii, structure) in structures {
+            let resolved = render(structure, &manifest)
+                .and_then(|each| resolve(each, options, &mut summary))
+                .map_err(|e| failed(ii, e))?;
+            pending.extend(resolved.map(|each| (ii, each)));
+        }
+        for (ii, each) in &pending {
+            check_writable(&each.target).map_err(|e| failed(*ii, e))?;
+        }
+
+        let mut written = Vec::new();
+        for (ii, each) in pending {
+            let (target, change) = (each.target.clone(), each.change);
+            let result = commit(
+                each,
+                &derfile.path,
+                &mut run,
+                &mut summary,
+                &mut manifest,
+                &mut written,
+            );
+            if let Err(e) = result {
+                return Err(failed(ii, rollback(&written, e)));
+            }
+            if change != Change::Unchanged {
+                changed[ii].push(target);
+            }
+        }
+    } else {
+        for (ii, structure) in structures {
+            let result = render(structure, &manifest).and_then(|pending| {
+                if options.dry_run {
+                    print_diff(&pending);
+                    summary
+                        .applied
+                        .push((pending.target.to_string_lossy().to_string(), pending.change));
+                    Ok(())
+                } else if let Some(pending) = resolve(pending, options, &mut summary)? {
+                    let (target, change) = (pending.target.clone(), pending.change);
+                    commit(
+                        pending,
+                        &derfile.path,
+                        &mut run,
+                        &mut summary,
+                        &mut manifest,
+                        &mut Vec::new(),
+                    )?;
+                    if change != Change::Unchanged {
+                        changed[ii].push(target);
+                    }
+                    Ok(())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Err(e) = result {
+                // Files written so far stay written, so they are recorded anyway.
+                if !options.dry_run {
+                    manifest.save(&manifest_path)?;
+                }
+                return Err(failed(ii, e));
+            }
+        }
+    }
+
+    if !options.dry_run {
+        manifest.save(&manifest_path)?;
+    }
+    if let Some(root) = backups {
+        backup::prune(&root, options.keep_backups)?;
+    }
+    if !options.dry_run {
+        for (template, changed) in templates.iter().zip(&changed) {
+            let vars = hook_vars(derfile, template, changed);
+            let mut result = Ok(());
+            if !changed.is_empty() {
+                result = run_hook(&template.hooks, "on_change", &template.name, &vars);
+            }
+            result
+                .and_then(|_| run_hook(&template.hooks, "after_apply", &template.name, &vars))
+                .map_err(|e| on_error(&template.hooks, &template.name, vars, e))?;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Environmental variables of the hooks of a template: the name of the template, its source and
+/// target, and the targets, which were changed.
+fn hook_vars(
+    derfile: &Derfile,
+    template: &Template,
+    changed: &[PathBuf],
+) -> Vec<(&'static str, String)> {
+    let source = Path::new(&template.name);
+    let name = derfile
+        .path
+        .parent()
+        .and_then(|dir| source.strip_prefix(dir).ok())
+        .unwrap_or(source);
+    let target = Path::new(&template.apply_path).join(&template.final_name);
+
+    vec![
+        ("DER_TEMPLATE", name.to_string_lossy().to_string()),
+        ("DER_SOURCE", template.name.clone()),
+        ("DER_TARGET", target.to_string_lossy().to_string()),
+        (
+            "DER_CHANGED",
+            changed
+                .iter()
+                .map(|each| each.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+    ]
+}
+
+/// Run a hook, if it's set. `name` is the template or derfile the hook belongs to.
+fn run_hook(hooks: &Hooks, field: &str, name: &str, vars: &[(&str, String)]) -> Result {
+    let Some(command) = hooks.get(field) else {
+        return Ok(());
+    };
+    if debug() {
+        println!("[\x1b[32mINFO\x1b[0m] Running {field} hook of {name}: {command}");
+    }
+    let output = execute_hook(command, vars).map_err(|e| match e {
+        Error::Custom(message) => format!("{message}, in {field} hook of: {name}").into(),
+        other => other,
+    })?;
+    if !output.is_empty() {
+        println!("{output}");
+    }
+
+    Ok(())
+}
+
+/// Run the `on_error` hook after `error`, with the error in `DER_ERROR`, and return the error.
+fn on_error(hooks: &Hooks, name: &str, mut vars: Vec<(&str, String)>, error: Error) -> Error {
+    vars.push(("DER_ERROR", error.plain_message()));
+    if let Err(e) = run_hook(hooks, "on_error", name, &vars) {
+        eprintln!("[\x1b[33mWARN\x1b[0m] {e}");
+    }
+
+    error
+}
+
+/// Check every file in the manifest, which was applied from a derfile, against its target and
+/// what its template renders to now.
+pub fn status(derfile: &Derfile, options: &ApplyOptions) -> Result<Vec<(Entry, Status)>> {
+    let (templates, _) = templates_for_host(derfile, options)?;
+    let manifest = Manifest::load(&Manifest::path()?)?;
+    let mut rendered = HashMap::new();
+    for structure in build(derfile, templates)? {
+        let pending = render(structure, &manifest)?;
+        rendered.insert(pending.target, pending.hash);
+    }
+
+    Ok(statuses(
+        manifest,
+        &derfile.path,
+        &rendered,
+        &targets(derfile)?,
+    ))
+}
+
+/// Status of every entry of a derfile, given the hashes of the targets rendered for the current
+/// host, and all targets of the derfile. Targets of templates for other hosts can only be checked
+/// against what der wrote, they aren't orphaned.
+fn statuses(
+    manifest: Manifest,
+    derfile: &Path,
+    rendered: &HashMap<PathBuf, Option<u64>>,
+    targets: &HashSet<PathBuf>,
+) -> Vec<(Entry, Status)> {
+    manifest
+        .entries
+        .into_values()
+        .filter(|entry| entry.belongs_to(derfile))
+        .map(|entry| {
+            let status = match rendered.get(&entry.target) {
+                None if targets.contains(&entry.target) => entry.local_status(),
+                rendered => entry.status(rendered.copied()),
+            };
+            (entry, status)
+        })
+        .collect()
+}
+
+/// Targets of all templates of a derfile, whichever host they are meant for.
+pub fn targets(derfile: &Derfile) -> Result<HashSet<PathBuf>> {
+    let templates = derfile.templates.values().cloned().collect();
+
+    Ok(build(derfile, templates)?.iter().map(output_path).collect())
+}
+
+/// Target of a template file or linked template directory.
+fn output_path(structure: &TemplateStructure) -> PathBuf {
+    match structure {
+        TemplateStructure::File(f) => f.output_path(),
+        TemplateStructure::Directory(dir) => dir.output_path(),
+    }
+}
+
+/// Remove temporary files, which an aborted run left next to targets. Targets, which are
+/// unchanged or not written in this run at all, would keep them forever otherwise.
+fn remove_stale_temp_files(targets: impl IntoIterator<Item = PathBuf>) -> Result {
+    for target in targets {
+        remove_stale_temp_file(&target)?;
+        // Files are written through links, next to the file they point to.
+        if let Ok(resolved) = fs::canonicalize(&target) {
+            if resolved != target {
+                remove_stale_temp_file(&resolved)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Select the templates, which are meant for the current host, and the skipped ones with the
+/// reason why.
+fn templates_for_host(
+    derfile: &Derfile,
+    options: &ApplyOptions,
+) -> Result<(Vec<Template>, Skipped)> {
+    let hostname = hostname()?;
+    let mut all_templates: Vec<&Template> = derfile.templates.values().collect();
+    all_templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut templates: Vec<Template> = Vec::new();
+    let mut skipped = Skipped::new();
+    for template in all_templates {
+        if options.ignore_hosts || is_for_host(template, &hostname)? {
+            templates.push(template.clone());
+        } else {
+            skipped.push((
+                template.name.clone(),
+                format!(
+                    "{hostname} is not in hostnames {}",
+                    template.serialize_hostnames()
+                ),
+            ));
+        }
+    }
+
+    Ok((templates, skipped))
+}
+
+/// All template files and linked template directories of some templates.
+fn build(derfile: &Derfile, templates: Vec<Template>) -> Result<Vec<TemplateStructure>> {
+    Ok(recursive_build(templates, &derfile.vars, &derfile.groups)?
+        .into_iter()
+        .filter(|structure| match structure {
+            TemplateStructure::File(_) => true,
+            TemplateStructure::Directory(dir) => dir.is_linked(),
+        })
+        .collect())
+}
+
+/// Render a template file, or a linked template directory, and compare it with its target and
+/// what der last wrote to it.
+fn render(structure: TemplateStructure, manifest: &Manifest) -> Result<Pending> {
+    let mut f = match structure {
+        TemplateStructure::File(f) => f,
+        TemplateStructure::Directory(dir) => return Ok(link_directory(dir)),
+    };
+    if debug() {
+        println!("[\x1b[32mINFO\x1b[0m] Applying: {}", f.0.path)
+    }
+    let parsed = f.parse()?;
+    let target = f.output_path();
+    let template = f.0.path.clone();
+    let mut permissions = f.permissions().map_err(|e| match e {
+        Error::Custom(message) => format!("{message}, in template: {template}").into(),
+        other => other,
+    })?;
+    let current = target.is_file().then(|| fs::read(&target)).transpose()?;
+    let hash = Some(manifest::hash(parsed.0.as_bytes()));
+    // Edits of linked targets go to their sources, so only copies can be modified.
+    let mut merged = None;
+    let modified = match (&current, manifest.entries.get(&target)) {
+        (Some(current), Some(entry)) if f.0.deploy == Deploy::Copy && entry.link.is_none() => {
+            let edited = entry
+                .hash
+                .is_some_and(|hash| hash != manifest::hash(current));
+            // Local changes, which were merged before, are kept until the template changes.
+            let has_merge = entry.hash != entry.rendered;
+            if has_merge && !edited && entry.rendered == hash {
+                merged = Some(String::from_utf8_lossy(current).to_string());
+            }
+            (edited || (has_merge && merged.is_none())) && *current != parsed.0.as_bytes()
+        }
+        _ => false,
+    };
+
+    let (output, change) = match f.0.deploy {
+        Deploy::Copy => {
+            let change = match &current {
+                _ if linked_by_der(&target, &template)? => Change::Changed,
+                None => Change::New,
+                Some(current) if *current == parsed.0.as_bytes() => Change::Unchanged,
+                Some(_) if merged.is_some() => Change::Unchanged,
+                Some(_) => Change::Changed,
+            };
+            (Output::File(Box::new(f), parsed), change)
+        }
+        Deploy::Symlink => {
+            let (source, rendered) = if f.1.as_deref() == Some(parsed.0.as_str()) {
+                // Changing the mode of the link would change the template itself.
+                permissions = FilePermissions::default();
+                (PathBuf::from(&template), None)
+            } else {
+                (build_path(&target)?, Some(parsed))
+            };
+            let change = link_change(
+                &target,
+                &source,
+                rendered.as_ref().map(|rendered| rendered.0.as_bytes()),
+            );
+            (Output::Link { source, rendered }, change)
+        }
+    };
+
+    Ok(Pending {
+        template,
+        target,
+        output,
+        current,
+        change,
+        permissions,
+        hash,
+        modified,
+        merged,
+    })
+}
+
+/// Link a template directory as a whole.
+fn link_directory(dir: TemplateDirectory) -> Pending {
+    if debug() {
+        println!("[\x1b[32mINFO\x1b[0m] Linking: {}", dir.settings.path)
+    }
+    let source = PathBuf::from(&dir.settings.path);
+    let target = dir.output_path();
+
+    Pending {
+        template: dir.settings.path,
+        change: link_change(&target, &source, None),
+        target,
+        output: Output::Link {
+            source,
+            rendered: None,
+        },
+        current: None,
+        permissions: FilePermissions::default(),
+        hash: None,
+        modified: false,
+        merged: None,
+    }
+}
+
+/// Directory, to which templates are rendered, when their targets are symlinks to them.
+pub fn build_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("build"))
+}
+
+/// Path, to which a template is rendered, when its target is a symlink to it. The build directory
+/// mirrors the paths of the targets, e.g. `~/.local/state/der/build/home/user/.bashrc`.
+pub fn build_path(target: &Path) -> Result<PathBuf> {
+    Ok(mirror_path(&build_dir()?, target))
+}
+
+/// Whether a target is a symlink made by der, which points either to its template, or into the
+/// build directory.
+fn linked_by_der(target: &Path, template: &str) -> Result<bool> {
+    let Ok(destination) = fs::read_link(target) else {
+        return Ok(false);
+    };
+
+    Ok(destination == Path::new(template) || destination.starts_with(build_dir()?))
+}
+
+/// How putting a symlink to `source` at `target` changes it. A link, which already points to
+/// `source`, is unchanged, unless `source` doesn't have the `rendered` contents yet.
+fn link_change(target: &Path, source: &Path, rendered: Option<&[u8]>) -> Change {
+    if !is_symlink(target) {
+        return match target.exists() {
+            true => Change::Changed,
+            false => Change::New,
+        };
+    }
+
+    let links_to_source = fs::read_link(target).is_ok_and(|destination| destination == source);
+    let is_rendered =
+        rendered.is_none_or(|rendered| fs::read(source).is_ok_and(|current| current == rendered));
+    if links_to_source && is_rendered {
+        Change::Unchanged
+    } else {
+        Change::Changed
+    }
+}
+
+/// Print how a template would change its target.
+fn print_diff(pending: &Pending) {
+    let target_name = pending.target.to_string_lossy();
+    println!("{}: {target_name}", pending.change);
+    if pending.modified {
+        println!("    {}", Status::Modified);
+    }
+    let parsed = match &pending.output {
+        Output::File(_, parsed) => parsed,
+        Output::Link { source, rendered } => {
+            if pending.change != Change::Unchanged {
+                println!("    -> {}", source.to_string_lossy());
+            }
+            let Some(rendered) = rendered else {
+                return;
+            };
+            rendered
+        }
+    };
+    print!(
+        "{}",
+        unified(
+            &String::from_utf8_lossy(pending.current.as_deref().unwrap_or_default()),
+            &parsed.0,
+            &target_name,
+            &pending.template,
+            true
+        )
+    );
+}
+
+/// Ask what to do with a target, which was changed since der wrote it, after showing the diff.
+/// Returns `None`, if the target is kept the way it is.
+fn resolve(
+    pending: Pending,
+    options: &ApplyOptions,
+    summary: &mut Summary,
+) -> Result<Option<Pending>> {
+    if !pending.modified || options.force {
+        return Ok(Some(pending));
+    }
+
+    print_diff(&pending);
+    let answer = match io::stdin().is_terminal() {
+        true => ask("Overwrite it, keep it, or merge the local changes? [o/K/m]")?,
+        false => String::new(),
+    };
+    match answer.as_str() {
+        "o" | "O" | "overwrite" => Ok(Some(pending)),
+        "m" | "M" | "merge" => merge_local(pending, summary),
+        _ => {
+            summary
+                .kept
+                .push(pending.target.to_string_lossy().to_string());
+            Ok(None)
+        }
+    }
+}
+
+/// Merge the local changes of a target with its rendered template, based on what der last wrote
+/// to it. If the changes conflict, the merge is written next to the target with conflict markers
+/// to be resolved by hand, and the target is kept.
+fn merge_local(mut pending: Pending, summary: &mut Summary) -> Result<Option<Pending>> {
+    let Output::File(_, parsed) = &pending.output else {
+        return Ok(Some(pending));
+    };
+    // Without a copy of what der wrote, the whole file is a conflict.
+    let base = fs::read_to_string(manifest::applied_path(&pending.target)?).unwrap_or_default();
+    let local = String::from_utf8_lossy(pending.current.as_deref().unwrap_or_default());
+    let (merged, conflicts) = merge(&base, &local, &parsed.0);
+    if conflicts == 0 {
+        pending.merged = Some(merged);
+        return Ok(Some(pending));
+    }
+
+    let mut path = pending.target.clone().into_os_string();
+    path.push(MERGE_SUFFIX);
+    let path = PathBuf::from(path);
+    write_atomic(&path, merged.as_bytes(), pending.permissions.mode)?;
+    println!(
+        "[\x1b[33mWARN\x1b[0m] Merging {} has {conflicts} conflict(s), resolve them in: {}",
+        pending.target.to_string_lossy(),
+        path.to_string_lossy()
+    );
+    summary
+        .kept
+        .push(pending.target.to_string_lossy().to_string());
+
+    Ok(None)
+}
+
+/// Put a rendered template at its target, backing up the file it replaces, and record it in the
+/// manifest as applied from `derfile`.
+fn commit(
+    pending: Pending,
+    derfile: &Path,
+    run: &mut Option<Run>,
+    summary: &mut Summary,
+    manifest: &mut Manifest,
+    written: &mut Vec<Written>,
+) -> Result {
+    let target = &pending.target;
+    let linked = is_symlink(target);
+    // Links made by der are just replaced, other links are written through as files.
+    let replaces_link = linked
+        && (matches!(pending.output, Output::Link { .. })
+            || linked_by_der(target, &pending.template)?);
+    if let (Change::Changed, false, Some(run)) = (pending.change, replaces_link, run) {
+        if target.is_file() {
+            run.save(target)?;
+            summary.backup = Some(run.id.clone());
+        }
+    }
+    let previous = if replaces_link {
+        Some(Previous::Link(fs::read_link(target)?))
+    } else {
+        pending.current.map(Previous::Contents)
+    };
+    let created = missing_dirs(target);
+    // Merged local changes are part of what's written, but not of the rendered template.
+    let written_hash = match &pending.merged {
+        Some(merged) => Some(manifest::hash(merged.as_bytes())),
+        None => pending.hash,
+    };
+
+    // Every file is recorded before it's written, so even a partial write can be rolled back.
+    match pending.output {
+        Output::File(file, parsed) => {
+            if !replaces_link
+                && fs::canonicalize(target).ok() == fs::canonicalize(&pending.template).ok()
+            {
+                // A parent directory of the target is a link to the template directory.
+                return Err(format!(
+                    "{} is its own template {}, remove the link to its directory first",
+                    target.to_string_lossy(),
+                    pending.template
+                )
+                .into());
+            }
+            written.push(Written::before(target, previous, false));
+            if replaces_link {
+                fs::remove_file(target)?;
+            }
+            let applied = parsed.0.clone();
+            let mode = pending.permissions.mode;
+            file.write(pending.merged.map(ParsedTemplate).unwrap_or(parsed), mode)?;
+            pending.permissions.apply(target)?;
+            let applied_path = manifest::applied_path(target)?;
+            written.push(Written::file(&applied_path));
+            write_if_changed(&applied_path, applied.as_bytes(), mode)?;
+        }
+        Output::Link { source, rendered } => {
+            if target.is_dir() && !linked {
+                return Err(format!(
+                    "{} is a directory, it can't be replaced by a link",
+                    target.to_string_lossy()
+                )
+                .into());
+            }
+            if let Some(rendered) = rendered {
+                written.push(Written::file(&source));
+                write_if_changed(&source, rendered.0.as_bytes(), pending.permissions.mode)?;
+                pending.permissions.apply(&source)?;
+            }
+            // Links, which already point to their source, are left alone.
+            if fs::read_link(target).ok() != Some(source.clone()) {
+                written.push(Written::before(target, previous, true));
+                symlink_atomic(&source, target)?;
+            }
+        }
+    }
+    manifest.record(
+        target,
+        &pending.template,
+        derfile,
+        written_hash,
+        pending.hash,
+    )?;
+    manifest.record_dirs(created);
+    summary
+        .applied
+        .push((target.to_string_lossy().to_string(), pending.change));
+    if debug() {
+        println!("[\x1b[32mINFO\x1b[0m] Done!");
+    }
+
+    Ok(())
+}
+
+/// Directories, which have to be created to write a target.
+fn missing_dirs(target: &Path) -> Vec<PathBuf> {
+    target
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// Check that a file can be written, without changing anything. The file itself can't be a
+/// directory and its nearest existing parent directory has to allow creating files in it.
+fn check_writable(target: &Path) -> Result {
+    let cant_write = |reason: String| -> Error {
+        format!("Can't write {}: {reason}", target.to_string_lossy()).into()
+    };
+    if target.is_dir() && !is_symlink(target) {
+        return Err(cant_write("it is a directory".to_string()));
+    }
+
+    let mut dir = target.parent().unwrap_or(Path::new("."));
+    while !dir.as_os_str().is_empty() && !dir.exists() {
+        dir = dir.parent().unwrap_or(Path::new("."));
+    }
+    if dir.as_os_str().is_empty() {
+        dir = Path::new(".");
+    }
+    if !dir.is_dir() {
+        return Err(cant_write(format!(
+            "{} is not a directory",
+            dir.to_string_lossy()
+        )));
+    }
+
+    let probe = temp_file_path(&dir.join(target.file_name().unwrap_or_default()));
+    fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .map_err(|e| cant_write(e.to_string()))?;
+    fs::remove_file(&probe)?;
+
+    Ok(())
+}
+
+/// Put back the previous contents and permissions of all written files after `error`, and remove
+/// the ones, which didn't exist before.
+fn rollback(written: &[Written], error: Error) -> Error {
+    let mut failed = Vec::new();
+    let mut rolled_back = 0;
+    for each in written.iter().rev() {
+        match each.undo() {
+            Ok(true) => {
+                rolled_back += 1;
+                if debug() {
+                    println!(
+                        "[\x1b[32mINFO\x1b[0m] Rolled back: {}",
+                        each.target.to_string_lossy()
+                    );
+                }
+            }
+            Ok(false) => (),
+            Err(e) => failed.push(format!("{}: {e}", each.target.to_string_lossy())),
+        }
+    }
+
+    if failed.is_empty() {
+        format!("{error}\nRolled back {rolled_back} already written file(s).").into()
+    } else {
+        format!(
+            "{error}\nUnable to roll back some files:\n    {}",
+            failed.join("\n    ")
+        )
+        .into()
+    }
+}
+
+/// The backup store, unless backups are turned off or nothing is written.
+fn backups_root(options: &ApplyOptions) -> Result<Option<std::path::PathBuf>> {
+    if options.dry_run || options.keep_backups == 0 {
+        return Ok(None);
+    }
+
+    backup::backups_dir().map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_writable, is_for_host, link_change, remove_stale_temp_files, rollback, statuses,
+        targets, Change, Previous, Written,
+    };
+    use crate::derfile::Template;
+    use crate::utils::TempDir;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn template_hosts() {
+        let mut template = Template::default();
+        assert!(is_for_host(&template, "laptop").unwrap());
+
+        template.add_hostname("server1".to_string());
+        template.add_hostname("*-laptop".to_string());
+        assert!(is_for_host(&template, "work-laptop").unwrap());
+        assert!(is_for_host(&template, "server1").unwrap());
+        assert!(!is_for_host(&template, "laptop").unwrap());
+    }
+
+    #[test]
+    fn transactions() {
+        let dir = TempDir::new("apply");
+        let old = dir.join("old");
+        let new = dir.join("deep/new");
+        let untouched = dir.join("untouched");
+        fs::write(&old, "old").unwrap();
+        fs::set_permissions(&old, fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(&untouched, "untouched").unwrap();
+
+        assert!(check_writable(&old).is_ok());
+        assert!(check_writable(&new).is_ok());
+        assert!(check_writable(&dir).is_err());
+        assert!(check_writable(&old.join("file")).is_err());
+        assert!(!dir.join(".new.der-tmp").exists());
+
+        let written = vec![
+            Written::before(&old, Some(Previous::Contents(b"old".to_vec())), false),
+            Written::before(&new, None, false),
+            Written::file(&untouched),
+        ];
+        fs::write(&old, "written").unwrap();
+        fs::set_permissions(&old, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::create_dir_all(new.parent().unwrap()).unwrap();
+        fs::write(&new, "written").unwrap();
+
+        let error = rollback(&written, "Disk full".to_string().into());
+        assert!(error.to_string().contains("Rolled back 2"));
+        assert_eq!(fs::read_to_string(&old).unwrap(), "old");
+        assert_eq!(
+            fs::metadata(&old).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!new.exists());
+        assert_eq!(fs::read_to_string(&untouched).unwrap(), "untouched");
+    }
+
+    #[test]
+    fn stale_temp_files() {
+        use crate::utils::{symlink_atomic, temp_file_path, write_if_changed};
+
+        let dir = TempDir::new("stale");
+        let unchanged = dir.join("unchanged");
+        let real = dir.join("real");
+        let link = dir.join("link");
+        fs::write(&unchanged, "same").unwrap();
+        fs::write(&real, "real").unwrap();
+        symlink_atomic(&real, &link).unwrap();
+        for target in [&unchanged, &real, &link] {
+            fs::write(temp_file_path(target), "aborted").unwrap();
+        }
+
+        remove_stale_temp_files([unchanged.clone(), link.clone()]).unwrap();
+        assert!(!temp_file_path(&unchanged).exists());
+        assert!(!temp_file_path(&link).exists());
+        assert!(!temp_file_path(&real).exists());
+        assert!(!write_if_changed(&unchanged, b"same", None).unwrap());
+        // Targets without a temporary file are fine too.
+        remove_stale_temp_files([unchanged, dir.join("missing")]).unwrap();
+    }
+
+    #[test]
+    fn links() {
+        use crate::utils::symlink_atomic;
+
+        let dir = TempDir::new("link");
+        let source = dir.join("source");
+        let other = dir.join("other");
+        let target = dir.join("out/target");
+        fs::write(&source, "rendered").unwrap();
+        fs::write(&other, "other").unwrap();
+
+        assert_eq!(link_change(&target, &source, None), Change::New);
+        symlink_atomic(&other, &target).unwrap();
+        assert_eq!(link_change(&target, &source, None), Change::Changed);
+        symlink_atomic(&source, &target).unwrap();
+        assert_eq!(fs::read_link(&target).unwrap(), source);
+        assert_eq!(link_change(&target, &source, None), Change::Unchanged);
+        assert_eq!(
+            link_change(&target, &source, Some(b"rendered")),
+            Change::Unchanged
+        );
+        assert_eq!(link_change(&target, &source, Some(b"new")), Change::Changed);
+
+        // The rendered file in the build directory is put back too.
+        let written = vec![
+            Written::file(&source),
+            Written::before(&target, Some(Previous::Link(other.clone())), true),
+        ];
+        fs::write(&source, "rendered again").unwrap();
+        rollback(&written, "Disk full".to_string().into());
+        assert_eq!(fs::read_link(&target).unwrap(), other);
+        assert_eq!(fs::read_to_string(&other).unwrap(), "other");
+        assert_eq!(fs::read_to_string(&source).unwrap(), "rendered");
+    }
+
+    #[test]
+    fn targets_of_all_hosts() {
+        use crate::config::Config;
+        use crate::derfile::Derfile;
+
+        let dir = TempDir::new("targets");
+        fs::write(dir.join("laptop.t"), "").unwrap();
+        fs::write(dir.join("server.t"), "").unwrap();
+        let derfile = "
+[laptop.t]
+final_name = laptop
+apply_path = out/
+hostnames = laptop
+
+[server.t]
+final_name = server
+apply_path = out/
+hostnames = server
+"
+        .to_string();
+        let derfile =
+            Derfile::load_derfile(derfile, &dir.join("derfile"), &Config::default()).unwrap();
+
+        assert_eq!(
+            targets(&derfile).unwrap(),
+            HashSet::from([dir.join("out/laptop"), dir.join("out/server")])
+        );
+    }
+
+    #[test]
+    fn statuses_of_the_derfile() {
+        use crate::manifest::{hash, Manifest, Status};
+
+        let dir = TempDir::new("status");
+        let derfile = dir.join("derfile");
+        let [rendered, other_host, orphan, other] =
+            dir.files(["rendered", "other_host", "orphan", "other"]);
+        let mut manifest = Manifest::default();
+        for target in [&rendered, &other_host, &orphan] {
+            let contents = fs::read(target).unwrap();
+            manifest
+                .record(
+                    target,
+                    "template.t",
+                    &derfile,
+                    Some(hash(&contents)),
+                    Some(hash(&contents)),
+                )
+                .unwrap();
+        }
+        manifest
+            .record(&other, "other.t", &dir.join("work/derfile"), None, None)
+            .unwrap();
+
+        let hashes = HashMap::from([(rendered.clone(), Some(hash(b"new")))]);
+        let targets = HashSet::from([rendered.clone(), other_host.clone()]);
+        let statuses: Vec<_> = statuses(manifest, &derfile, &hashes, &targets)
+            .into_iter()
+            .map(|(entry, status)| (entry.target, status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (orphan, Status::Orphaned),
+                (other_host, Status::UpToDate),
+                (rendered, Status::OutOfDate),
+            ]
+        );
+    }
+
+    #[test]
+    fn merged_changes() {
+        use super::{build, commit, merge_local, render, Summary};
+        use crate::config::Config;
+        use crate::derfile::Derfile;
+        use crate::manifest::{hash, Manifest, Status};
+
+        // Apply like `der -a`, but merge local changes instead of asking.
+        fn apply_merging(derfile: &Derfile, manifest: &mut Manifest) -> Change {
+            let templates = derfile.templates.values().cloned().collect();
+            let mut structures = build(derfile, templates).unwrap();
+            assert_eq!(structures.len(), 1);
+            let mut summary = Summary::default();
+            let mut pending = render(structures.remove(0), manifest).unwrap();
+            if pending.modified {
+                pending = merge_local(pending, &mut summary).unwrap().unwrap();
+            }
+            let change = pending.change;
+            let path = derfile.path.clone();
+            commit(
+                pending,
+                &path,
+                &mut None,
+                &mut summary,
+                manifest,
+                &mut Vec::new(),
+            )
+            .unwrap();
+            change
+        }
+
+        let dir = TempDir::new("merge");
+        std::env::set_var("XDG_STATE_HOME", dir.join("state"));
+        let template = dir.join("config.t");
+        let target = dir.join("out/config");
+        let derfile = "[config.t]\nfinal_name = config\napply_path = out/\n".to_string();
+        let derfile =
+            Derfile::load_derfile(derfile, &dir.join("derfile"), &Config::default()).unwrap();
+        let mut manifest = Manifest::default();
+
+        fs::write(&template, "1\n2\n3\n4\n5\n").unwrap();
+        assert_eq!(apply_merging(&derfile, &mut manifest), Change::New);
+        fs::write(&target, "one\n2\n3\n4\n5\n").unwrap();
+        fs::write(&template, "1\n2\n3\n4\nfive\n").unwrap();
+        assert_eq!(apply_merging(&derfile, &mut manifest), Change::Changed);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "one\n2\n3\n4\nfive\n");
+
+        // The merge is what der wrote, and the template didn't change since.
+        let rendered = Some(Some(hash(b"1\n2\n3\n4\nfive\n")));
+        assert_eq!(manifest.entries[&target].status(rendered), Status::UpToDate);
+        assert_eq!(apply_merging(&derfile, &mut manifest), Change::Unchanged);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "one\n2\n3\n4\nfive\n");
+
+        // The merged local changes are kept, when the template changes again.
+        fs::write(&template, "1\n2\nthree\n4\nfive\n").unwrap();
+        assert_eq!(apply_merging(&derfile, &mut manifest), Change::Changed);
+        assert_eq!(
+            fs::read_to_string(&target).unwrap(),
+            "one\n2\nthree\n4\nfive\n"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Syntax tree of template files.
+//!
+//! Template files are parsed into a tree of nodes, so that substitution blocks and loops can be
+//! nested inside of each other to any depth. Every node keeps the lines of the template file it
+//! was parsed from, so rendering the tree reproduces the text byte for byte and tools, such as
+//! linters or previews, can point to the exact place in the file.
+
+use crate::condition;
+use crate::error::*;
+use crate::lexer::{tokenize, Line, TokenKind};
+use crate::pattern::split_patterns;
+use crate::template::{
+    TEMP_ELSE, TEMP_END, TEMP_ESCAPE, TEMP_FOR, TEMP_FOR_END, TEMP_NOT, TEMP_START,
+};
+
+/// A single piece of a template file.
+#[derive(Debug, Clone)]
+pub enum Node<'a> {
+    /// A line of text. Variable references in it are replaced with their values on output.
+    Text(Line<'a>),
+    /// A line of text with an escaped block symbol or directive, split around the escaping
+    /// `\`, which is left out of the output.
+    Escaped(Line<'a>, &'a str, &'a str),
+    /// An include directive with the path of the included file.
+    Include(Line<'a>, &'a str),
+    /// A substitution block. Only the first of its branches, whose condition matches the current
+    /// hostname is output.
+    Block(Vec<Branch<'a>>),
+    /// A loop, which outputs its contents once for every value of a list.
+    Loop(Loop<'a>),
+}
+
+/// One branch of a substitution block, either the `@@` branch or one of the `@:` branches.
+#[derive(Debug, Clone)]
+pub struct Branch<'a> {
+    /// Line of this branch's symbol.
+    pub line: Line<'a>,
+    /// The unparsed condition of this branch.
+    pub header: &'a str,
+    pub condition: Condition,
+    /// Everything in between this branch's symbol and the next symbol of the same block.
+    pub children: Vec<Node<'a>>,
+}
+
+/// A loop over the values of a list variable, or a list of comma separated values.
+#[derive(Debug, Clone)]
+pub struct Loop<'a> {
+    /// Line of the loop's begin symbol.
+    pub line: Line<'a>,
+    /// Name of the variable every value is bound to.
+    pub name: &'a str,
+    /// Either a `$variable` or a list of comma separated values.
+    pub list: &'a str,
+    pub children: Vec<Node<'a>>,
+}
+
+/// When should a branch of a substitution block be output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Hostname matches one of these patterns.
+    Hostnames(Vec<String>),
+    /// Hostname matches none of these patterns.
+    NotHostnames(Vec<String>),
+    /// A condition expression is true, see the `condition` module.
+    Expression(condition::Expr),
+    /// Always, used by a bare `@:` branch.
+    Else,
+}
+
+/// A block or a loop, which has been opened, but not yet closed while parsing.
+enum Open<'a> {
+    /// Line of the begin symbol and branches so far.
+    Block(Line<'a>, Vec<Branch<'a>>),
+    Loop(Loop<'a>),
+}
+
+/// Parse the contents of a template file into a tree of nodes.
+///
+/// Every begin symbol pushes a new block or loop on a stack, every else symbol adds a new branch
+/// to the innermost block and every end symbol pops the innermost block or loop from the stack and
+/// attaches it to its parent, so nested blocks and loops always pair up correctly. Lines of
+/// unbalanced symbols are pointed to by the returned error.
+///
+/// Symbols and directives can be written behind any of the `comments` prefixes, see
+/// `lexer::tokenize`.
+pub fn parse<'a>(contents: &'a str, comments: &[String]) -> Result<Vec<Node<'a>>> {
+    let mut root: Vec<Node> = Vec::new();
+    let mut stack: Vec<Open> = Vec::new();
+
+    for token in tokenize(contents, comments) {
+        let line = token.line;
+        let directive = token.directive;
+
+        let node = match token.kind {
+            TokenKind::Text => Node::Text(line),
+            TokenKind::Escaped(escaped) => Node::Escaped(
+                line,
+                &line.text[..escaped],
+                &line.text[escaped + TEMP_ESCAPE.len()..],
+            ),
+            TokenKind::BlockStart(header) => {
+                if header.trim().is_empty() {
+                    return Err(line.error(
+                        directive,
+                        format!("template block symbol `{TEMP_START}` without a list of hostnames or a condition!"),
+                    ));
+                }
+                let branch = Branch {
+                    line,
+                    header: header.trim(),
+                    condition: parse_condition(header, line)?,
+                    children: Vec::new(),
+                };
+                stack.push(Open::Block(line, vec![branch]));
+                continue;
+            }
+            TokenKind::Else(header) => {
+                let symbol = &directive[..TEMP_ELSE.len()];
+                let Some(Open::Block(_, branches)) = stack.last_mut() else {
+                    return Err(line.error(
+                        symbol,
+                        format!("template block symbol `{TEMP_ELSE}` outside of a template block!"),
+                    ));
+                };
+                if branches.last().map(|branch| &branch.condition) == Some(&Condition::Else) {
+                    return Err(line.error(
+                        symbol,
+                        format!("template block symbol `{TEMP_ELSE}` after the final `{TEMP_ELSE}` branch of a template block!"),
+                    ));
+                }
+                let condition = if header.trim().is_empty() {
+                    Condition::Else
+                } else {
+                    parse_condition(header, line)?
+                };
+                branches.push(Branch {
+                    line,
+                    header: header.trim(),
+                    condition,
+                    children: Vec::new(),
+                });
+                continue;
+            }
+            TokenKind::Include(include_path) => Node::Include(line, include_path),
+            TokenKind::For(header) => {
+                stack.push(Open::Loop(parse_loop(header, line)?));
+                continue;
+            }
+            TokenKind::EndFor => match stack.pop() {
+                Some(Open::Loop(each)) => Node::Loop(each),
+                Some(Open::Block(opened, _)) => {
+                    return Err(line.error(
+                        directive,
+                        format!(
+                            "`{TEMP_FOR_END}` closes a template block opened on line {}, expected `{TEMP_END}`!",
+                            opened.number
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(line.error(
+                        directive,
+                        format!("`{TEMP_FOR_END}` without an open loop!"),
+                    ))
+                }
+            },
+            TokenKind::End => match stack.pop() {
+                Some(Open::Block(_, branches)) => Node::Block(branches),
+                Some(Open::Loop(each)) => {
+                    return Err(line.error(
+                        directive,
+                        format!(
+                            "`{TEMP_END}` closes a loop opened on line {}, expected `{TEMP_FOR_END}`!",
+                            each.line.number
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(line.error(
+                        directive,
+                        format!("closing template block symbol `{TEMP_END}` without an open template block!"),
+                    ))
+                }
+            },
+        };
+
+        match stack.last_mut() {
+            Some(Open::Block(_, branches)) => branches
+                .last_mut()
+                .expect("blocks always have a branch")
+                .children
+                .push(node),
+            Some(Open::Loop(each)) => each.children.push(node),
+            None => root.push(node),
+        }
+    }
+
+    match stack.last() {
+        Some(Open::Block(line, _)) => Err(line.error(
+            line.text.trim(),
+            format!("template block opened with `{TEMP_START}` is never closed!"),
+        )),
+        Some(Open::Loop(each)) => Err(each.line.error(
+            each.line.text.trim(),
+            format!("loop opened with `{TEMP_FOR}` is never closed!"),
+        )),
+        None => Ok(root),
+    }
+}
+
+/// Parse the header of a loop, which looks like `name in $list` or `name in a, b, c`.
+fn parse_loop<'a>(header: &'a str, line: Line<'a>) -> Result<Loop<'a>> {
+    let header = header.trim();
+    let Some((name, list)) = header
+        .split_once(char::is_whitespace)
+        .and_then(|(name, rest)| Some((name, rest.trim_start().strip_prefix("in")?)))
+        .filter(|(_, list)| list.starts_with(char::is_whitespace))
+    else {
+        return Err(line.error(
+            header,
+            format!("invalid loop, expected `{TEMP_FOR} name in $list`!"),
+        ));
+    };
+
+    Ok(Loop {
+        line,
+        name,
+        list: list.trim(),
+        children: Vec::new(),
+    })
+}
+
+/// Parse the header of a block branch, which is either a list of comma separated hostnames,
+/// optionally prefixed with `!`, or a condition expression.
+fn parse_condition(header: &str, line: Line) -> Result<Condition> {
+    let header = header.trim();
+
+    if let Some(rest) = header.strip_prefix(TEMP_NOT) {
+        return Ok(Condition::NotHostnames(split_patterns(rest)));
+    }
+    if condition::is_condition(header) {
+        return condition::parse(header)
+            .map(Condition::Expression)
+            .map_err(|e| line.in_line(e, header, "invalid block condition"));
+    }
+
+    Ok(Condition::Hostnames(split_patterns(header)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, Condition, Node};
+
+    #[test]
+    fn tree() {
+        let nodes = parse("a\n@@ laptop\n@@for x in 1, 2\nb\n@@end\n@:\nc\n@!\n", &[]).unwrap();
+
+        assert_eq!(nodes.len(), 2);
+        assert!(matches!(nodes[0], Node::Text(line) if line.text == "a"));
+        let Node::Block(branches) = &nodes[1] else {
+            panic!("expected a block");
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(branches[0].header, "laptop");
+        assert_eq!(branches[0].line.number, 2);
+        assert!(matches!(&branches[0].children[..], [Node::Loop(each)] if each.list == "1, 2"));
+        assert_eq!(branches[1].condition, Condition::Else);
+    }
+}
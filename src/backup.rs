@@ -0,0 +1,301 @@
+//! Backups of files, which are overwritten when applying a derfile.
+//!
+//! Every run of `der -a`, which replaces at least one existing file, gets its own directory in the
+//! backup store, named after the time of the run, e.g.
+//! `~/.local/state/der/backups/20240101-120000/`. It holds copies of the replaced files and an
+//! index with their original paths and metadata, so single files or whole runs can be restored
+//! later. Only the newest `keep_backups` runs are kept.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::*;
+use crate::utils::{self, debug, state_dir};
+
+/// How many runs are kept in the backup store, if not set in the config file.
+pub const DEFAULT_KEEP_BACKUPS: usize = 10;
+/// Name of the index file inside of a run's backup directory.
+const INDEX: &str = "index";
+/// Name of the directory with the copied files inside of a run's backup directory.
+const FILES: &str = "files";
+
+/// A file saved in the backup store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackedUpFile {
+    /// Where the file was, before it was replaced.
+    pub path: PathBuf,
+    /// The copy of the file in the backup store.
+    pub copy: PathBuf,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time in seconds since the UNIX epoch.
+    pub modified: u64,
+}
+
+/// All the files backed up during a single run.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    /// Identifier of the run, which is the time it started at.
+    pub id: String,
+    pub dir: PathBuf,
+    pub files: Vec<BackedUpFile>,
+}
+
+/// Backups made during the current run. The run's directory is only created once the first file
+/// is saved, so runs, which don't replace anything, don't show up in the backup store.
+#[derive(Debug)]
+pub struct Run {
+    pub id: String,
+    dir: PathBuf,
+    files: usize,
+}
+
+/// Path of the backup store.
+pub fn backups_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("backups"))
+}
+
+impl Run {
+    /// Start a new run in the backup store at `root`.
+    pub fn new(root: &Path) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let base = timestamp(now.as_secs());
+        let mut id = base.clone();
+        let mut ii = 1;
+        while root.join(&id).exists() {
+            ii += 1;
+            id = format!("{base}-{ii}");
+        }
+
+        Self {
+            dir: root.join(&id),
+            id,
+            files: 0,
+        }
+    }
+
+    /// Save a copy of a file with its metadata, before it is replaced or removed.
+    pub fn save(&mut self, path: &Path) -> Result<BackedUpFile> {
+        let metadata = fs::metadata(path)?;
+        fs::create_dir_all(self.dir.join(FILES))?;
+
+        self.files += 1;
+        let copy = self.dir.join(FILES).join(self.files.to_string());
+        fs::copy(path, &copy)?;
+
+        let file = BackedUpFile {
+            path: path.to_path_buf(),
+            copy,
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            modified: metadata.mtime().max(0) as u64,
+        };
+        let mut index = fs::read_to_string(self.dir.join(INDEX)).unwrap_or_default();
+        index.push_str(&format!(
+            "{}\t{:o}\t{}\t{}\t{}\t{}\n",
+            self.files,
+            file.mode,
+            file.uid,
+            file.gid,
+            file.modified,
+            path.to_string_lossy()
+        ));
+        fs::write(self.dir.join(INDEX), index)?;
+
+        if debug() {
+            println!(
+                "[\x1b[32mINFO\x1b[0m] Backed up {} to {}",
+                path.to_string_lossy(),
+                file.copy.to_string_lossy()
+            );
+        }
+
+        Ok(file)
+    }
+}
+
+/// List all runs in the backup store, the oldest one first.
+pub fn list(root: &Path) -> Result<Vec<Backup>> {
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let dir = entry?.path();
+        let Ok(index) = fs::read_to_string(dir.join(INDEX)) else {
+            continue;
+        };
+        let files = index
+            .lines()
+            .filter_map(|line| parse_index_line(&dir, line))
+            .collect();
+        ret.push(Backup {
+            id: dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            dir,
+            files,
+        });
+    }
+    ret.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(ret)
+}
+
+fn parse_index_line(dir: &Path, line: &str) -> Option<BackedUpFile> {
+    let mut split = line.splitn(6, '\t');
+    let copy = dir.join(FILES).join(split.next()?);
+    let mode = u32::from_str_radix(split.next()?, 8).ok()?;
+    let uid = split.next()?.parse().ok()?;
+    let gid = split.next()?.parse().ok()?;
+    let modified = split.next()?.parse().ok()?;
+
+    Some(BackedUpFile {
+        path: PathBuf::from(split.next()?),
+        copy,
+        mode,
+        uid,
+        gid,
+        modified,
+    })
+}
+
+/// Restore all files of a run, or only the one at `file`, to their original paths. Files, which
+/// are currently at those paths, are backed up in a new run first.
+pub fn restore(root: &Path, id: &str, file: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let Some(backup) = list(root)?.into_iter().find(|backup| backup.id == id) else {
+        return Err(format!("No backup with the id: {id}").into());
+    };
+    let files: Vec<&BackedUpFile> = backup
+        .files
+        .iter()
+        .filter(|each| file.is_none_or(|file| each.path == file))
+        .collect();
+    if files.is_empty() {
+        return Err(format!(
+            "Backup {id} has no file {}",
+            file.unwrap_or(Path::new("")).to_string_lossy()
+        )
+        .into());
+    }
+
+    let mut run = Run::new(root);
+    let mut ret = Vec::new();
+    for each in files {
+        if each.path.exists() {
+            run.save(&each.path)?;
+        } else if let Some(parent) = each.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::copy(&each.copy, &each.path)?;
+        fs::File::options()
+            .write(true)
+            .open(&each.path)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(each.modified))?;
+        utils::set_owner_and_mode(&each.path, Some(each.uid), Some(each.gid), Some(each.mode))?;
+        ret.push(each.path.clone());
+    }
+
+    Ok(ret)
+}
+
+/// Remove all but the newest `keep` runs from the backup store.
+pub fn prune(root: &Path, keep: usize) -> Result {
+    let backups = list(root)?;
+    for backup in backups.iter().take(backups.len().saturating_sub(keep)) {
+        fs::remove_dir_all(&backup.dir)?;
+        if debug() {
+            println!("[\x1b[32mINFO\x1b[0m] Removed old backup: {}", backup.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Format seconds since the UNIX epoch as a UTC timestamp like `20240101-120000`.
+fn timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{list, prune, restore, timestamp, Run};
+    use crate::utils::TempDir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(951827696), "20000229-123456");
+        assert_eq!(timestamp(1704067199), "20231231-235959");
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let dir = TempDir::new("backup");
+        let root = dir.join("backups");
+        let target = dir.join("out/config");
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(&target, "old").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut run = Run::new(&root);
+        run.save(&target).unwrap();
+        fs::write(&target, "new").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let backups = list(&root).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].id, run.id);
+        assert_eq!(backups[0].files[0].path, target);
+        assert_eq!(backups[0].files[0].mode & 0o777, 0o600);
+
+        assert!(restore(&root, &run.id, Some(&dir.join("nope"))).is_err());
+        assert_eq!(
+            restore(&root, &run.id, Some(&target)).unwrap(),
+            vec![target.clone()]
+        );
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // The replaced file got backed up by the restore.
+        assert_eq!(list(&root).unwrap().len(), 2);
+
+        prune(&root, 1).unwrap();
+        let backups = list(&root).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_ne!(backups[0].id, run.id);
+    }
+}
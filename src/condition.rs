@@ -0,0 +1,452 @@
+//! Conditions, which can be used in headers of substitution blocks instead of a plain list of
+//! hostnames. A condition is a small boolean expression, which is evaluated against derfile
+//! variables and facts about the current host, for example:
+//!
+//! ```text
+//! @@ os == linux and distro in (arch, debian) and not exists(sway)
+//! @@ $theme != "light" or hostname in $laptops
+//! ```
+//!
+//! Operands are derfile variables (`$name`), host groups (`%name`), host facts (`hostname`, `os`,
+//! `distro`, `arch`), quoted strings and plain words. An operand on its own, without any
+//! comparison, is true when the current hostname matches one of its values, so
+//! `laptop or "*-desktop"` behaves like a hostname list, including hostname patterns.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path;
+
+use crate::derfile::{expand_groups, Groups, Variable, GROUP_PREF, VAR_PREF};
+use crate::error::*;
+use crate::pattern::{match_hostname, split_patterns, REGEX_PREF};
+
+/// Names of host facts, which can be used as operands.
+pub const FACTS: [&str; 4] = ["hostname", "os", "distro", "arch"];
+
+/// Everything a condition can be evaluated against.
+#[derive(Debug, Clone, Copy)]
+pub struct Facts<'a> {
+    /// Name of the host the templates are parsed for.
+    pub hostname: &'a str,
+    /// Derfile and config variables.
+    pub vars: &'a HashMap<String, Variable>,
+    /// Host groups defined in the derfile.
+    pub groups: &'a Groups,
+}
+
+/// A parsed condition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Eq(Operand, Operand),
+    NotEq(Operand, Operand),
+    /// Any value of the left operand is one of the values on the right.
+    In(Operand, Vec<Operand>),
+    /// A command with this name can be found in `$PATH`.
+    Exists(String),
+    /// An environmental variable with this name is set.
+    Env(String),
+    /// The current hostname matches one of the values of the operand as a hostname pattern.
+    Host(Operand),
+}
+
+/// A value inside of a condition.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Var(String),
+    /// A host group, its values are all of its members.
+    Group(String),
+    Fact(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    Comma,
+    Eq,
+    NotEq,
+    /// A quoted string.
+    Str(String),
+    /// Anything else: keywords, variables, facts and literals.
+    Word(String),
+}
+
+const KEYWORDS: [&str; 4] = ["and", "or", "not", "in"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut ret = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                ret.push(match c {
+                    '(' => Token::LeftParen,
+                    ')' => Token::RightParen,
+                    _ => Token::Comma,
+                });
+            }
+            '=' | '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err(format!("expected `{c}=`").into());
+                }
+                ret.push(if c == '=' { Token::Eq } else { Token::NotEq });
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => string.push(c),
+                        None => return Err("unterminated string".to_string().into()),
+                    }
+                }
+                ret.push(Token::Str(string));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "(),\"=!".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                ret.push(Token::Word(word));
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Whether a block header is a condition rather than a plain list of hostname patterns.
+pub fn is_condition(header: &str) -> bool {
+    !split_patterns(header).iter().all(|entry| {
+        entry.starts_with(REGEX_PREF)
+            || !(entry.contains(char::is_whitespace)
+                || entry.contains(['(', ')', '"'])
+                || entry.contains("==")
+                || entry.contains("!="))
+    })
+}
+
+/// Parse a condition from a block header.
+pub fn parse(input: &str) -> Result<Expr> {
+    let tokens = tokenize(input)?;
+    let mut parser = ConditionParser { tokens, index: 0 };
+    let expr = parser.or()?;
+
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {token:?}").into()),
+    }
+}
+
+struct ConditionParser {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl ConditionParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == keyword)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected {expected:?}, found {token:?}").into()),
+            None => Err(format!("expected {expected:?}, found end of condition").into()),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut left = self.and()?;
+        while self.peek_keyword("or") {
+            self.next();
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while self.peek_keyword("and") {
+            self.next();
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.peek_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::LeftParen) {
+            self.next();
+            let expr = self.or()?;
+            self.expect(Token::RightParen)?;
+            return Ok(expr);
+        }
+
+        for function in ["exists", "env"] {
+            if self.peek_keyword(function)
+                && self.tokens.get(self.index + 1) == Some(&Token::LeftParen)
+            {
+                self.next();
+                self.next();
+                let argument = match self.next() {
+                    Some(Token::Word(word)) | Some(Token::Str(word)) => word,
+                    _ => return Err(format!("expected an argument to `{function}`").into()),
+                };
+                self.expect(Token::RightParen)?;
+                return Ok(if function == "exists" {
+                    Expr::Exists(argument)
+                } else {
+                    Expr::Env(argument)
+                });
+            }
+        }
+
+        let left = self.operand()?;
+        match self.peek() {
+            Some(Token::Eq) => {
+                self.next();
+                Ok(Expr::Eq(left, self.operand()?))
+            }
+            Some(Token::NotEq) => {
+                self.next();
+                Ok(Expr::NotEq(left, self.operand()?))
+            }
+            Some(Token::Word(word)) if word == "in" => {
+                self.next();
+                Ok(Expr::In(left, self.list()?))
+            }
+            _ => Ok(Expr::Host(left)),
+        }
+    }
+
+    /// Either a parenthesized, comma separated list of operands, or a single operand.
+    fn list(&mut self) -> Result<Vec<Operand>> {
+        if self.peek() != Some(&Token::LeftParen) {
+            return Ok(vec![self.operand()?]);
+        }
+        self.next();
+
+        let mut ret = vec![self.operand()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.next();
+            ret.push(self.operand()?);
+        }
+        self.expect(Token::RightParen)?;
+
+        Ok(ret)
+    }
+
+    fn operand(&mut self) -> Result<Operand> {
+        match self.next() {
+            Some(Token::Str(string)) => Ok(Operand::Literal(string)),
+            Some(Token::Word(word)) if KEYWORDS.contains(&word.as_str()) => {
+                Err(format!("expected a value, found `{word}`").into())
+            }
+            Some(Token::Word(word)) => {
+                if let Some(name) = word.strip_prefix(VAR_PREF) {
+                    Ok(Operand::Var(name.to_string()))
+                } else if word.starts_with(GROUP_PREF) {
+                    Ok(Operand::Group(word))
+                } else if FACTS.contains(&word.as_str()) {
+                    Ok(Operand::Fact(word))
+                } else {
+                    Ok(Operand::Literal(word))
+                }
+            }
+            Some(token) => Err(format!("expected a value, found {token:?}").into()),
+            None => Err("expected a value, found end of condition"
+                .to_string()
+                .into()),
+        }
+    }
+}
+
+impl Expr {
+    /// Evaluate the condition. Referencing an undefined variable is an error.
+    pub fn eval(&self, facts: &Facts) -> Result<bool> {
+        Ok(match self {
+            Self::And(left, right) => left.eval(facts)? && right.eval(facts)?,
+            Self::Or(left, right) => left.eval(facts)? || right.eval(facts)?,
+            Self::Not(expr) => !expr.eval(facts)?,
+            Self::Eq(left, right) => left.value(facts)?.join(",") == right.value(facts)?.join(","),
+            Self::NotEq(left, right) => {
+                left.value(facts)?.join(",") != right.value(facts)?.join(",")
+            }
+            Self::In(left, list) => {
+                let mut values = Vec::new();
+                for each in list {
+                    values.append(&mut each.value(facts)?);
+                }
+                left.value(facts)?.iter().any(|each| values.contains(each))
+            }
+            Self::Exists(command) => command_exists(command),
+            Self::Env(name) => env::var_os(name).is_some(),
+            Self::Host(operand) => {
+                match_hostname(&operand.value(facts)?, facts.hostname)?.is_some()
+            }
+        })
+    }
+}
+
+impl Operand {
+    fn value(&self, facts: &Facts) -> Result<Vec<String>> {
+        match self {
+            Self::Var(name) => match facts.vars.get(name) {
+                Some(variable) => Ok(variable.value.clone()),
+                None => Err(format!("undefined variable `{name}`").into()),
+            },
+            Self::Group(name) => expand_groups(&[name], facts.groups),
+            Self::Fact(name) => Ok(vec![match name.as_str() {
+                "hostname" => facts.hostname.to_string(),
+                "os" => env::consts::OS.to_string(),
+                "arch" => env::consts::ARCH.to_string(),
+                _ => distro(),
+            }]),
+            Self::Literal(value) => Ok(vec![value.clone()]),
+        }
+    }
+}
+
+/// Read the `ID` of the current distribution from `/etc/os-release`, or an empty string if it
+/// can't be found.
+fn distro() -> String {
+    let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches('"').to_string())
+        .unwrap_or_default()
+}
+
+/// Whether an executable file with this name exists in one of the directories in `$PATH`.
+fn command_exists(command: &str) -> bool {
+    if command.contains('/') {
+        return path::Path::new(command).is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(command).is_file()))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(condition: &str, hostname: &str) -> bool {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "theme".to_string(),
+            Variable::new("theme".to_string(), vec!["dark".to_string()]),
+        );
+        vars.insert(
+            "laptops".to_string(),
+            Variable::new(
+                "laptops".to_string(),
+                vec!["t480".to_string(), "x1".to_string()],
+            ),
+        );
+        let mut groups = Groups::new();
+        groups.insert("gpu".to_string(), vec!["desktop".to_string()]);
+        let facts = Facts {
+            hostname,
+            vars: &vars,
+            groups: &groups,
+        };
+        parse(condition).unwrap().eval(&facts).unwrap()
+    }
+
+    #[test]
+    fn test_is_condition() {
+        assert!(!is_condition(" host1, host2"));
+        assert!(!is_condition("build-01"));
+        assert!(is_condition("os == linux"));
+        assert!(is_condition("hostname in $laptops"));
+        assert!(is_condition("exists(sway)"));
+        assert!(is_condition("laptop or desktop"));
+        assert!(!is_condition(r"re:^(gpu|cpu)-box\d{1,2}$, *-laptop"));
+        assert!(!is_condition(""));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert!(eval("$theme == dark", "x1"));
+        assert!(eval("$theme != \"light\"", "x1"));
+        assert!(eval("hostname in $laptops", "x1"));
+        assert!(!eval("hostname in $laptops", "desktop"));
+        assert!(eval("hostname in (desktop, server)", "desktop"));
+        assert!(eval(&format!("os == {}", env::consts::OS), "x1"));
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        assert!(eval("desktop or $laptops", "t480"));
+        assert!(!eval("not $laptops", "t480"));
+        assert!(eval("$theme == dark and not (desktop or server)", "t480"));
+        assert!(!eval(
+            "$theme == dark and hostname == server or desktop",
+            "t480"
+        ));
+        assert!(eval("%gpu or server", "desktop"));
+        assert!(eval("hostname in %gpu", "desktop"));
+        assert!(!eval("%gpu", "t480"));
+    }
+
+    #[test]
+    fn test_predicates() {
+        assert!(eval("exists(sh)", "x1"));
+        assert!(!eval("exists(surely-not-a-real-command)", "x1"));
+        assert!(eval("env(PATH)", "x1"));
+        assert!(!eval("env(SURELY_NOT_A_REAL_VARIABLE)", "x1"));
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        assert!(parse("os ==").is_err());
+        assert!(parse("(os == linux").is_err());
+        assert!(parse("os = linux").is_err());
+        assert!(parse("os == linux laptop").is_err());
+
+        let vars = HashMap::new();
+        let groups = Groups::new();
+        let facts = Facts {
+            hostname: "x1",
+            vars: &vars,
+            groups: &groups,
+        };
+        assert!(parse("$nope == a").unwrap().eval(&facts).is_err());
+    }
+}
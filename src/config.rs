@@ -3,17 +3,30 @@ use std::fmt;
 use std::fs;
 use std::path;
 
-use crate::derfile::{Template, Variable};
+use crate::backup::DEFAULT_KEEP_BACKUPS;
+use crate::derfile::{parse_mode_rule, Deploy, Template, Variable};
 use crate::derfile::{CODE_KEYWORDS, CODE_SEP, VAR_PREF};
 use crate::error::*;
-use crate::utils::{debug, execute_code};
+use crate::utils::{debug, execute_code, parse_mode};
 
 type Variables = Vec<Variable>;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) template: Template,
     pub(crate) vars: Variables,
+    /// How many runs are kept in the backup store, no backups are made if 0.
+    pub keep_backups: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            template: Template::default(),
+            vars: Variables::default(),
+            keep_backups: DEFAULT_KEEP_BACKUPS,
+        }
+    }
 }
 
 impl Config {
@@ -22,7 +35,7 @@ impl Config {
         if let Ok(read_file) = fs::read_to_string(path) {
             Config::parse(&read_file)
         } else {
-            return Err(format!("Unable to read config file: {}", path.to_string_lossy()).into());
+            Err(format!("Unable to read config file: {}", path.to_string_lossy()).into())
         }
     }
 
@@ -75,22 +88,20 @@ impl Config {
                     }
                 } else {
                     match left_part {
+                        // The default config file has these fields with empty values.
                         "extensions" => {
-                            if right_part.contains(',') {
-                                for each in right_part.split(',') {
-                                    config.template.add_extension(each.to_string())
-                                }
-                            } else {
-                                config.template.add_extension(right_part.to_string())
+                            for each in right_part.split(',').filter(|x| !x.trim().is_empty()) {
+                                config.template.add_extension(each.trim().to_string())
                             }
                         }
                         "hostnames" => {
-                            if right_part.contains(',') {
-                                for each in right_part.split(',') {
-                                    config.template.add_hostname(each.to_string())
-                                }
-                            } else {
-                                config.template.add_hostname(right_part.to_string())
+                            for each in right_part.split(',').filter(|x| !x.trim().is_empty()) {
+                                config.template.add_hostname(each.trim().to_string())
+                            }
+                        }
+                        "comments" => {
+                            for each in right_part.split(',') {
+                                config.template.add_comment(each.trim().to_string())
                             }
                         }
                         "recursive" => {
@@ -107,6 +118,30 @@ impl Config {
                                 config.template.set_parse_files(false)
                             }
                         }
+                        "mode" => config.template.set_mode(
+                            parse_mode(right_part)
+                                .ok_or_else(|| format!("Invalid value of mode: {right_part}"))?,
+                        ),
+                        "deploy" => config.template.set_deploy(
+                            Deploy::parse(right_part)
+                                .ok_or_else(|| format!("Invalid value of deploy: {right_part}"))?,
+                        ),
+                        "owner" => config.template.set_owner(right_part.to_string()),
+                        "group" => config.template.set_group(right_part.to_string()),
+                        "mode_rules" => {
+                            for each in right_part.split(',') {
+                                config.template.add_mode_rule(
+                                    parse_mode_rule(each.trim())
+                                        .ok_or_else(|| format!("Invalid mode rule: {each}"))?,
+                                )
+                            }
+                        }
+                        "preserve_mode" => config.template.set_preserve_mode(right_part == "true"),
+                        "keep_backups" => {
+                            config.keep_backups = right_part.parse().map_err(|_| {
+                                format!("Invalid value of keep_backups: {right_part}")
+                            })?
+                        }
                         _ => (),
                     }
                 }
@@ -146,6 +181,26 @@ impl fmt::Display for Config {
         writeln!(f, "extensions = {}", self.template.serialize_extensions())?;
         writeln!(f, "recursive = {}", self.template.recursive)?;
         writeln!(f, "parse_files = {}", self.template.parse_files)?;
+        writeln!(f, "comments = {}", self.template.serialize_comments())?;
+        if let Some(mode) = self.template.mode {
+            writeln!(f, "mode = {mode:o}")?;
+        }
+        if let Some(owner) = &self.template.owner {
+            writeln!(f, "owner = {owner}")?;
+        }
+        if let Some(group) = &self.template.group {
+            writeln!(f, "group = {group}")?;
+        }
+        if !self.template.mode_rules.is_empty() {
+            writeln!(f, "mode_rules = {}", self.template.serialize_mode_rules())?;
+        }
+        if let Some(preserve_mode) = self.template.preserve_mode {
+            writeln!(f, "preserve_mode = {preserve_mode}")?;
+        }
+        if let Some(deploy) = self.template.deploy {
+            writeln!(f, "deploy = {deploy}")?;
+        }
+        writeln!(f, "keep_backups = {}", self.keep_backups)?;
         for var in &self.vars {
             writeln!(f, "${} = {}", var._name, var.serialize())?;
         }
@@ -161,4 +216,21 @@ mod test {
     fn config() {
         println!("{}", Config::load_default().unwrap())
     }
+
+    #[test]
+    fn empty_fields() {
+        let config = Config::parse(&Config::default().to_string()).unwrap();
+        assert!(config.template.hostnames.is_empty());
+        assert!(config.template.extensions.is_empty());
+
+        let config = Config::parse(&"hostnames = laptop, desktop").unwrap();
+        assert_eq!(config.template.hostnames, vec!["laptop", "desktop"]);
+    }
+
+    #[test]
+    fn keep_backups() {
+        assert_eq!(Config::parse(&"").unwrap().keep_backups, 10);
+        assert_eq!(Config::parse(&"keep_backups = 3").unwrap().keep_backups, 3);
+        assert!(Config::parse(&"keep_backups = many").is_err());
+    }
 }
@@ -5,6 +5,7 @@ use std::path;
 
 use crate::config::*;
 use crate::error::*;
+use crate::pattern::split_patterns;
 use crate::utils::*;
 
 /// Symbols for derfile parsing, these can be changed before compilation.
@@ -13,6 +14,7 @@ pub const TEMPLATE_RIGHT: &str = "]";
 pub const CODE_SEP: &str = "`";
 pub const VAR_PREF: &str = "$";
 pub const VAR_ADD: &str = ":"; // variable separator for adding values to a variable
+pub const GROUP_PREF: &str = "%";
 pub const CODE_KEYWORDS: [&str; 1] = ["env"];
 
 /// A template section of a derfile.
@@ -26,7 +28,7 @@ pub struct Template {
     pub hostnames: Vec<String>,
     /// A path to the directory in which the parsed file should be placed.
     pub apply_path: String,
-    /// [Dirctory only fields]
+    /// [Directory only fields]
     /// Whether all template files found in this directory and its subdirectories should be parsed.
     pub parse_files: bool,
     /// Extensions to look for when searching a directory for template files.
@@ -35,8 +37,59 @@ pub struct Template {
     /// be performed. If false, only the directory will be visited, all its subdirectories
     /// ignored.
     pub recursive: bool,
+    /// Comment prefixes, behind which block symbols can be written in the template files.
+    /// Prefixes like `vim:"` only apply to files with the given extension.
+    pub comments: Vec<String>,
+    /// Mode of the written files, like `0644`.
+    pub mode: Option<u32>,
+    /// User, which should own the written files, either a name or an id.
+    pub owner: Option<String>,
+    /// Group, which should own the written files, either a name or an id.
+    pub group: Option<String>,
+    /// [Directory only field]
+    /// Modes of files matching a glob, like `*.sh:755`, which take precedence over `mode`.
+    pub mode_rules: Vec<ModeRule>,
+    /// Whether written files should get the mode of their template file, unless `mode` or
+    /// `mode_rules` set one.
+    pub preserve_mode: Option<bool>,
+    /// Whether the output is copied to the apply path, or linked there, with `deploy = symlink`.
+    pub deploy: Option<Deploy>,
+    /// Commands, which run when the template is applied.
+    pub hooks: Hooks,
 }
 
+/// Shell commands, which run at certain points while applying a template, or a whole derfile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hooks {
+    /// Runs before anything is written.
+    pub before_apply: Option<String>,
+    /// Runs after all files were written.
+    pub after_apply: Option<String>,
+    /// Runs after all files were written, if the contents of any of them changed.
+    pub on_change: Option<String>,
+    /// Runs when applying fails.
+    pub on_error: Option<String>,
+}
+
+/// How the output of a template is put in place.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Deploy {
+    /// The rendered template is written to the apply path.
+    #[default]
+    Copy,
+    /// A symlink is put at the apply path, pointing to the template itself if it renders to
+    /// itself, or to the rendered template in the build directory. Directories with `parse_files =
+    /// false` are linked as a whole.
+    Symlink,
+}
+
+/// A glob and the mode of files matching it. Globs without a `/` are matched against the name of
+/// a file, other globs against its path inside of the template directory.
+pub type ModeRule = (String, u32);
+
+/// Host groups: names of groups and their members, which are hostname patterns or other groups.
+pub type Groups = HashMap<String, Vec<String>>;
+
 /// A single derfile variable.
 #[derive(Debug, Clone, Default)]
 pub struct Variable {
@@ -52,6 +105,10 @@ pub struct Derfile {
     pub templates: HashMap<String, Template>,
     /// Key value pairs of variable names and their values.
     pub vars: HashMap<String, Variable>,
+    /// Host groups defined in the derfile.
+    pub groups: Groups,
+    /// Hooks of the whole run, which are set before the first template.
+    pub hooks: Hooks,
     /// Absolute path to derfile.
     pub(crate) path: path::PathBuf,
     /// Which fileds are empty
@@ -88,6 +145,34 @@ impl Template {
         self.extensions.push(ext)
     }
 
+    pub fn add_comment(&mut self, comment: String) {
+        self.comments.push(comment)
+    }
+
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = Some(mode)
+    }
+
+    pub fn set_owner(&mut self, owner: String) {
+        self.owner = Some(owner)
+    }
+
+    pub fn set_group(&mut self, group: String) {
+        self.group = Some(group)
+    }
+
+    pub fn add_mode_rule(&mut self, rule: ModeRule) {
+        self.mode_rules.push(rule)
+    }
+
+    pub fn set_preserve_mode(&mut self, arg: bool) {
+        self.preserve_mode = Some(arg)
+    }
+
+    pub fn set_deploy(&mut self, deploy: Deploy) {
+        self.deploy = Some(deploy)
+    }
+
     pub(crate) fn serialize_hostnames(&self) -> String {
         self.hostnames.join(",")
     }
@@ -95,6 +180,47 @@ impl Template {
     pub(crate) fn serialize_extensions(&self) -> String {
         self.extensions.join(",")
     }
+
+    pub(crate) fn serialize_comments(&self) -> String {
+        self.comments.join(",")
+    }
+
+    pub(crate) fn serialize_mode_rules(&self) -> String {
+        self.mode_rules
+            .iter()
+            .map(|(glob, mode)| format!("{glob}:{mode:o}"))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+impl Hooks {
+    /// Names of the hook fields, in the order they run in.
+    pub const FIELDS: [&'static str; 4] = ["before_apply", "on_change", "after_apply", "on_error"];
+
+    /// Set a hook by the name of its field. Returns false if there is no such hook.
+    pub fn set(&mut self, field: &str, command: String) -> bool {
+        let hook = match field {
+            "before_apply" => &mut self.before_apply,
+            "after_apply" => &mut self.after_apply,
+            "on_change" => &mut self.on_change,
+            "on_error" => &mut self.on_error,
+            _ => return false,
+        };
+        *hook = Some(command);
+        true
+    }
+
+    /// Get a hook by the name of its field.
+    pub fn get(&self, field: &str) -> Option<&str> {
+        match field {
+            "before_apply" => self.before_apply.as_deref(),
+            "after_apply" => self.after_apply.as_deref(),
+            "on_change" => self.on_change.as_deref(),
+            "on_error" => self.on_error.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 impl Variable {
@@ -164,7 +290,7 @@ impl Derfile {
 
             // Then we get a mutable reference to the new template so we can parse and isert data
             // into it.
-            let mut new_template = new_derfile.get_template(&template_name).unwrap();
+            let new_template = new_derfile.get_template(&template_name).unwrap();
             new_template.name = template_name.to_string();
 
             // Then we check through all fields, parse them and add them to the new defile.
@@ -314,6 +440,24 @@ impl Derfile {
                 new_template.extensions = extensions_clone;
             }
 
+            if template.comments.is_empty() {
+                new_template.comments = default_template.comments.clone();
+            } else {
+                new_template.comments = template.comments.clone();
+            }
+
+            new_template.mode = template.mode.or(default_template.mode);
+            new_template.owner = template.owner.clone().or(default_template.owner.clone());
+            new_template.group = template.group.clone().or(default_template.group.clone());
+            if template.mode_rules.is_empty() {
+                new_template.mode_rules = default_template.mode_rules.clone();
+            } else {
+                new_template.mode_rules = template.mode_rules.clone();
+            }
+            new_template.preserve_mode = template.preserve_mode.or(default_template.preserve_mode);
+            new_template.deploy = template.deploy.or(default_template.deploy);
+            new_template.hooks = template.hooks.clone();
+
             if (self_clone.empty_fields & 0b00001000) == 0 {
                 new_template.recursive = default_template.recursive;
             } else {
@@ -327,6 +471,8 @@ impl Derfile {
             }
         }
         new_derfile.vars = self.vars.clone();
+        new_derfile.groups = self.groups.clone();
+        new_derfile.hooks = self.hooks.clone();
         new_derfile.path = self.path.clone();
 
         if debug() {
@@ -358,12 +504,16 @@ impl Derfile {
             .map(|each| each.0)
             .collect();
 
-        let var_lines: Vec<String> = lines
+        let var_lines: Vec<(usize, String)> = lines
             .clone()
             .enumerate()
             .filter(|line| line.1.trim().starts_with(VAR_PREF))
-            .map(|each| each.1.to_string())
+            .map(|each| (each.0 + 1, each.1.to_string()))
             .collect();
+        let in_file = |line_number: usize, line: &str, part: &str, message: String| -> Error {
+            Error::from(Diagnostic::at(line_number, line, part, message))
+                .in_file(&path.to_string_lossy())
+        };
 
         // [x] variables from shell code
         // [x] stuff such as "echo $PATH" does not work
@@ -376,7 +526,7 @@ impl Derfile {
         // What we do instead, is have a special keyword for returning environmental variables. So
         // something like this: "env`$PATH`" could return the actual value of our environmental
         // variable.
-        for line in var_lines.iter() {
+        for (line_number, line) in var_lines.iter() {
             if line.contains('=') {
                 let split = line.split_at(line.find('=').unwrap());
                 let name = split.0.trim().strip_prefix(VAR_PREF).unwrap().to_string();
@@ -387,7 +537,12 @@ impl Derfile {
                     if let Some(index) = right_side.find(CODE_SEP) {
                         let content = right_side[index + 1..right_side.len() - 1].to_string();
                         value = vec![execute_code(content.clone()).map_err(|_| {
-                            format!("Unable to execute code inside a code block: {content}")
+                            in_file(
+                                *line_number,
+                                line,
+                                split.1.trim_start_matches('=').trim(),
+                                format!("Unable to execute code inside a code block: {content}"),
+                            )
                         })?];
                     }
                 } else if right_side.contains(',') {
@@ -424,6 +579,46 @@ impl Derfile {
             }
         }
 
+        // Group definitions look like "%group = host1, host2, %other_group".
+        let mut group_lines: HashMap<String, (usize, &str)> = HashMap::new();
+        for (ii, line) in lines
+            .clone()
+            .enumerate()
+            .filter(|line| line.1.trim().starts_with(GROUP_PREF))
+        {
+            if let Some((name, members)) = line.split_once('=') {
+                let name = name.trim().strip_prefix(GROUP_PREF).unwrap().to_string();
+                derfile.groups.insert(name.clone(), split_patterns(members));
+                group_lines.insert(name, (ii + 1, line));
+            }
+        }
+        for (name, (line_number, line)) in group_lines.iter() {
+            expand_groups(&[format!("{GROUP_PREF}{name}")], &derfile.groups).map_err(
+                |e| match e {
+                    Error::Custom(message) => in_file(
+                        *line_number,
+                        line,
+                        line.split_once('=').map_or(line, |x| x.1.trim()),
+                        message,
+                    ),
+                    other => other,
+                },
+            )?;
+        }
+        // Hooks before the first template are the hooks of the whole run.
+        for line in lines
+            .clone()
+            .take(template_indecies.first().copied().unwrap_or(usize::MAX))
+        {
+            if let Some((field, command)) = line.split_once('=') {
+                if Hooks::FIELDS.contains(&field.trim()) {
+                    derfile.hooks.set(field.trim(), command.trim().to_string());
+                }
+            }
+        }
+
+        let mut hostname_lines: HashMap<String, (usize, String)> = HashMap::new();
+
         let lines: Vec<String> = lines.clone().map(|x| x.to_string()).collect();
         for (ii, index) in template_indecies.iter().enumerate() {
             let template_lines: Vec<String> =
@@ -438,7 +633,8 @@ impl Derfile {
 
             let mut template_name: String =
                 template_lines[0][1..template_lines[0].len() - 1].to_string();
-            for line in template_lines.iter() {
+            for (jj, line) in template_lines.iter().enumerate() {
+                let line_number = index + jj + 1;
                 if line.starts_with(TEMPLATE_LEFT) && line.ends_with(TEMPLATE_RIGHT) {
                     let mut derfile_dir_path =
                         path.to_owned().clone().parent().unwrap().to_path_buf();
@@ -467,22 +663,14 @@ impl Derfile {
                         }
                         "hostnames" => {
                             if let Some(table) = derfile.get_template(&template_name) {
-                                if split.1.contains(',') {
-                                    let value_list: Vec<&str> = split.1.split(',').collect();
-                                    for each in value_list {
-                                        if let Some(s) = each.strip_prefix('=') {
-                                            table.add_hostname(s.trim().to_string())
-                                        } else {
-                                            table.add_hostname(each.trim().to_string())
-                                        }
-                                    }
-                                    derfile.empty_fields |= 0b00000010;
-                                } else {
-                                    table.add_hostname(
-                                        split.1.strip_prefix('=').unwrap().trim().to_string(),
-                                    );
-                                    derfile.empty_fields |= 0b00000010;
+                                // Hostnames can be patterns, which may contain commas themselves.
+                                let field = split.1.strip_prefix('=').unwrap();
+                                for each in split_patterns(field) {
+                                    table.add_hostname(each)
                                 }
+                                hostname_lines
+                                    .insert(template_name.clone(), (line_number, line.clone()));
+                                derfile.empty_fields |= 0b00000010;
                             }
                         }
                         "apply_path" => {
@@ -529,11 +717,96 @@ impl Derfile {
                                 }
                             }
                         }
+                        "comments" => {
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                let field = split.1.strip_prefix('=').unwrap();
+                                for comment in field.split(',') {
+                                    table.add_comment(comment.trim().to_string());
+                                }
+                                derfile.empty_fields |= 0b01000000;
+                            }
+                        }
+                        "mode" => {
+                            let field = split.1.strip_prefix('=').unwrap().trim();
+                            let Some(mode) = parse_mode(field) else {
+                                return Err(in_file(
+                                    line_number,
+                                    line,
+                                    field,
+                                    format!("Invalid mode, expected a file mode like 644: {field}"),
+                                ));
+                            };
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                table.set_mode(mode);
+                            }
+                        }
+                        "deploy" => {
+                            let field = split.1.strip_prefix('=').unwrap().trim();
+                            let Some(deploy) = Deploy::parse(field) else {
+                                return Err(in_file(
+                                    line_number,
+                                    line,
+                                    field,
+                                    format!("Invalid deploy, expected copy or symlink: {field}"),
+                                ));
+                            };
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                table.set_deploy(deploy);
+                            }
+                        }
+                        "owner" => {
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                let field = split.1.strip_prefix('=').unwrap().trim();
+                                table.set_owner(field.to_string());
+                            }
+                        }
+                        "group" => {
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                let field = split.1.strip_prefix('=').unwrap().trim();
+                                table.set_group(field.to_string());
+                            }
+                        }
+                        "mode_rules" => {
+                            let field = split.1.strip_prefix('=').unwrap();
+                            for rule in field.split(',') {
+                                let rule = rule.trim();
+                                let Some(parsed) = parse_mode_rule(rule) else {
+                                    return Err(in_file(
+                                        line_number,
+                                        line,
+                                        rule,
+                                        format!("Invalid mode rule, expected glob:mode: {rule}"),
+                                    ));
+                                };
+                                if let Some(table) = derfile.get_template(&template_name) {
+                                    table.add_mode_rule(parsed);
+                                }
+                            }
+                        }
+                        "preserve_mode" => {
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                let field = split.1.strip_prefix('=').unwrap().trim();
+                                table.set_preserve_mode(field == "true");
+                            }
+                        }
+                        field if Hooks::FIELDS.contains(&field) => {
+                            if let Some(table) = derfile.get_template(&template_name) {
+                                let command = split.1.strip_prefix('=').unwrap().trim();
+                                table.hooks.set(field, command.to_string());
+                            }
+                        }
                         some => {
-                            if some.starts_with(VAR_PREF) {
+                            if some.starts_with(VAR_PREF) || some.starts_with(GROUP_PREF) {
                                 continue;
                             } else {
-                                eprintln!("WARN: {} is not a valid template filed!", split.0)
+                                let mut diagnostic = Diagnostic::at(
+                                    line_number,
+                                    line,
+                                    split.0.trim(),
+                                    format!("{} is not a valid template field!", split.0.trim()),
+                                );
+                                diagnostic.path = path.to_string_lossy().to_string();
+                                eprintln!("[\x1b[33mWARN\x1b[0m] {diagnostic}")
                             }
                         }
                     }
@@ -544,10 +817,104 @@ impl Derfile {
         d.with_config(config);
         derfile = d.parse();
 
+        for (name, template) in derfile.templates.iter_mut() {
+            template.hostnames =
+                expand_groups(&template.hostnames, &derfile.groups).map_err(|e| {
+                    match (e, hostname_lines.get(name)) {
+                        (Error::Custom(message), Some((line_number, line))) => in_file(
+                            *line_number,
+                            line,
+                            line.split_once('=').map_or(line.as_str(), |x| x.1.trim()),
+                            message,
+                        ),
+                        (other, _) => other,
+                    }
+                })?;
+        }
+
         Ok(derfile)
     }
 }
 
+impl Deploy {
+    /// Parse the value of a `deploy` field.
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "copy" => Some(Self::Copy),
+            "symlink" => Some(Self::Symlink),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Deploy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Copy => write!(f, "copy"),
+            Self::Symlink => write!(f, "symlink"),
+        }
+    }
+}
+
+/// Parse a mode rule like `*.sh:755`. The mode is after the last `:`, so globs can contain `:`.
+pub fn parse_mode_rule(rule: &str) -> Option<ModeRule> {
+    let (glob, mode) = rule.rsplit_once(':')?;
+    let glob = glob.trim();
+    (!glob.is_empty()).then_some(())?;
+    Some((glob.to_string(), parse_mode(mode)?))
+}
+
+/// Replace every group in a list of hostname patterns with all of its members, recursively.
+/// Groups which (indirectly) contain themselves, or which are not defined are an error.
+pub fn expand_groups<S: AsRef<str>>(list: &[S], groups: &Groups) -> Result<Vec<String>> {
+    fn expand(
+        entry: &str,
+        groups: &Groups,
+        visiting: &mut Vec<String>,
+        ret: &mut Vec<String>,
+    ) -> Result {
+        let Some(name) = entry.strip_prefix(GROUP_PREF) else {
+            if !ret.iter().any(|each| each == entry) {
+                ret.push(entry.to_string());
+            }
+            return Ok(());
+        };
+
+        if visiting.iter().any(|each| each == name) {
+            visiting.push(name.to_string());
+            return Err(format!(
+                "Host group cycle: {GROUP_PREF}{}",
+                visiting.join(&format!(" -> {GROUP_PREF}"))
+            )
+            .into());
+        }
+        let Some(members) = groups.get(name) else {
+            return Err(match visiting.last() {
+                Some(parent) => format!(
+                    "Host group {GROUP_PREF}{parent} has an unknown member: {GROUP_PREF}{name}"
+                ),
+                None => format!("Unknown host group: {GROUP_PREF}{name}"),
+            }
+            .into());
+        };
+
+        visiting.push(name.to_string());
+        for member in members {
+            expand(member, groups, visiting, ret)?;
+        }
+        visiting.pop();
+
+        Ok(())
+    }
+
+    let mut ret = Vec::new();
+    for entry in list {
+        expand(entry.as_ref(), groups, &mut Vec::new(), &mut ret)?;
+    }
+
+    Ok(ret)
+}
+
 impl fmt::Display for Template {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "[{}]", self.name)?;
@@ -556,7 +923,33 @@ impl fmt::Display for Template {
         writeln!(f, "hostnames = {}", self.serialize_hostnames())?;
         writeln!(f, "recursive = {}", self.recursive)?;
         writeln!(f, "parse_files = {}", self.parse_files)?;
-        writeln!(f, "extensions = {}", self.serialize_extensions())
+        writeln!(f, "extensions = {}", self.serialize_extensions())?;
+        writeln!(f, "comments = {}", self.serialize_comments())?;
+        if let Some(mode) = self.mode {
+            writeln!(f, "mode = {mode:o}")?;
+        }
+        if let Some(owner) = &self.owner {
+            writeln!(f, "owner = {owner}")?;
+        }
+        if let Some(group) = &self.group {
+            writeln!(f, "group = {group}")?;
+        }
+        if !self.mode_rules.is_empty() {
+            writeln!(f, "mode_rules = {}", self.serialize_mode_rules())?;
+        }
+        if let Some(preserve_mode) = self.preserve_mode {
+            writeln!(f, "preserve_mode = {preserve_mode}")?;
+        }
+        if let Some(deploy) = self.deploy {
+            writeln!(f, "deploy = {deploy}")?;
+        }
+        for field in Hooks::FIELDS {
+            if let Some(command) = self.hooks.get(field) {
+                writeln!(f, "{field} = {command}")?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -576,6 +969,16 @@ impl fmt::Display for Derfile {
             writeln!(f, "{}", variable.1)?;
         }
 
+        for (name, members) in self.groups.iter() {
+            writeln!(f, "{GROUP_PREF}{name} = {}", members.join(", "))?;
+        }
+
+        for field in Hooks::FIELDS {
+            if let Some(command) = self.hooks.get(field) {
+                writeln!(f, "{field} = {command}")?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -608,7 +1011,7 @@ recursive = true
         let derfile_result =
             Derfile::load_derfile(derfile_string, Path::new("some_path"), &Config::default());
 
-        assert_eq!(derfile_result.is_ok(), true);
+        assert!(derfile_result.is_ok());
     }
 
     #[test]
@@ -645,4 +1048,178 @@ extensions = t, g, h
             "some, real, weird, hostnames".to_string()
         );
     }
+
+    #[test]
+    fn host_groups() {
+        let derfile_string = r"#
+%laptops = t480, *-laptop
+%workstations = %laptops, desktop
+%gpu = desktop, re:^build-\d{1,2}$
+
+[some/name.t]
+final_name = name
+apply_path = some/path/
+hostnames = %workstations, %gpu, server
+            "
+        .to_string();
+        let derfile =
+            Derfile::load_derfile(derfile_string, Path::new("some_path"), &Config::default())
+                .unwrap();
+        let template = derfile
+            .templates
+            .iter()
+            .filter(|t| t.0 != "[default-template]")
+            .last()
+            .unwrap()
+            .1;
+
+        assert_eq!(
+            template.serialize_hostnames(),
+            r"t480,*-laptop,desktop,re:^build-\d{1,2}$,server".to_string()
+        );
+    }
+
+    #[test]
+    fn invalid_host_groups() {
+        let cycle = "%a = host, %b\n%b = %c\n%c = %a\n".to_string();
+        let error = Derfile::load_derfile(cycle, Path::new("some_path"), &Config::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("cycle"));
+
+        let unknown = "%a = host, %b\n".to_string();
+        let error = Derfile::load_derfile(unknown, Path::new("some_path"), &Config::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("%a has an unknown member: %b"));
+
+        let unknown_in_template = "[a.t]\nhostnames = %nope\n".to_string();
+        assert!(Derfile::load_derfile(
+            unknown_in_template,
+            Path::new("some_path"),
+            &Config::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn comments() {
+        let derfile_string = r#"
+[some/name.t]
+final_name = init.vim
+apply_path = some/path/
+comments = #, vim:", lua:--
+            "#
+        .to_string();
+        let derfile =
+            Derfile::load_derfile(derfile_string, Path::new("some_path"), &Config::default())
+                .unwrap();
+        let template = derfile
+            .templates
+            .iter()
+            .filter(|t| t.0 != "[default-template]")
+            .last()
+            .unwrap()
+            .1;
+
+        assert_eq!(template.serialize_comments(), r#"#,vim:",lua:--"#);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let derfile_string =
+            "$v = 1\n\n[a.t]\nfinal_name = a\nhostnames = host, %nope\n".to_string();
+        let error =
+            Derfile::load_derfile(derfile_string, Path::new("dir/derfile"), &Config::default())
+                .unwrap_err();
+        let Error::Diagnostic(diagnostic) = error else {
+            panic!("expected a diagnostic, got: {error}");
+        };
+        assert_eq!(diagnostic.path, "dir/derfile");
+        assert_eq!(diagnostic.line, 5);
+        assert_eq!(diagnostic.columns, 13..24);
+        assert!(diagnostic.message.contains("Unknown host group: %nope"));
+    }
+
+    #[test]
+    fn permissions() {
+        let mut config = Config::default();
+        config.template.set_owner("root".to_string());
+        config.template.set_mode(0o644);
+        let derfile_string = r"
+[some/bin]
+apply_path = some/path/
+mode_rules = *.sh:755, secret/*: 0600
+preserve_mode = true
+
+[some/ssh.t]
+final_name = config
+mode = 0o600
+deploy = symlink
+group = wheel
+            "
+        .to_string();
+        let derfile =
+            Derfile::load_derfile(derfile_string, Path::new("some_path"), &config).unwrap();
+        let bin = &derfile.templates["some/bin"];
+        let ssh = &derfile.templates["some/ssh.t"];
+
+        assert_eq!(bin.serialize_mode_rules(), "*.sh:755,secret/*:600");
+        assert_eq!(bin.preserve_mode, Some(true));
+        assert_eq!(bin.mode, Some(0o644));
+        assert_eq!(bin.owner.as_deref(), Some("root"));
+        assert_eq!(ssh.mode, Some(0o600));
+        assert_eq!(ssh.group.as_deref(), Some("wheel"));
+        assert!(ssh.mode_rules.is_empty());
+        assert_eq!(ssh.deploy, Some(Deploy::Symlink));
+        assert_eq!(bin.deploy, None);
+
+        let error = Derfile::load_derfile(
+            "[a.t]\nmode = rw-r--r--\n".to_string(),
+            Path::new("derfile"),
+            &Config::default(),
+        )
+        .unwrap_err();
+        let Error::Diagnostic(diagnostic) = error else {
+            panic!("expected a diagnostic, got: {error}");
+        };
+        assert_eq!((diagnostic.line, diagnostic.columns), (2, 8..17));
+
+        // The deploy strategy isn't a mode anymore, and a mode isn't a deploy strategy.
+        for field in ["mode = symlink", "deploy = 644"] {
+            let derfile = format!("[a.t]\n{field}\n");
+            assert!(
+                Derfile::load_derfile(derfile, Path::new("derfile"), &Config::default()).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let derfile_string = r"
+before_apply = echo start
+on_error = notify-send 'der failed'
+
+[some/i3.t]
+final_name = config
+on_change = i3-msg reload
+after_apply = test a=b
+            "
+        .to_string();
+        let derfile =
+            Derfile::load_derfile(derfile_string, Path::new("some_path"), &Config::default())
+                .unwrap();
+        let i3 = &derfile.templates["some/i3.t"];
+
+        assert_eq!(derfile.hooks.before_apply.as_deref(), Some("echo start"));
+        assert_eq!(
+            derfile.hooks.on_error.as_deref(),
+            Some("notify-send 'der failed'")
+        );
+        assert_eq!(derfile.hooks.on_change, None);
+        assert_eq!(i3.hooks.on_change.as_deref(), Some("i3-msg reload"));
+        assert_eq!(i3.hooks.get("after_apply"), Some("test a=b"));
+        assert_eq!(i3.hooks.before_apply, None);
+        assert!(!Hooks::default().set("on_success", String::new()));
+    }
 }
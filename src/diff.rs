@@ -0,0 +1,322 @@
+//! Line based diffs of text files, printed in the unified format, like `diff -u` does, and three
+//! way merges of them, like `diff3 -m` does.
+//!
+//! Lines are compared including their line endings, so a changed line ending or a missing final
+//! newline shows up as a change too.
+
+/// Number of unchanged lines shown around every change.
+pub const CONTEXT: usize = 3;
+
+/// A single line of a diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit<'a> {
+    /// The line is in both texts.
+    Equal(&'a str),
+    /// The line is only in the old text.
+    Delete(&'a str),
+    /// The line is only in the new text.
+    Insert(&'a str),
+}
+
+impl<'a> Edit<'a> {
+    fn line(&self) -> &'a str {
+        match self {
+            Self::Equal(line) | Self::Delete(line) | Self::Insert(line) => line,
+        }
+    }
+}
+
+/// Compute the shortest list of edits, which turns `old` into `new`, using the longest common
+/// subsequence of their lines.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<Edit<'a>> {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+
+    // Common prefix and suffix don't need the quadratic part.
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // lengths[i][j] is the length of the longest common subsequence of old_middle[i..] and
+    // new_middle[j..].
+    let width = new_middle.len() + 1;
+    let mut lengths = vec![0usize; (old_middle.len() + 1) * width];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i * width + j] = if old_middle[i] == new_middle[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut edits: Vec<Edit> = old[..prefix].iter().map(|line| Edit::Equal(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() && j < new_middle.len() {
+        if old_middle[i] == new_middle[j] {
+            edits.push(Edit::Equal(old_middle[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            edits.push(Edit::Delete(old_middle[i]));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(new_middle[j]));
+            j += 1;
+        }
+    }
+    edits.extend(old_middle[i..].iter().map(|line| Edit::Delete(line)));
+    edits.extend(new_middle[j..].iter().map(|line| Edit::Insert(line)));
+    edits.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| Edit::Equal(line)),
+    );
+
+    edits
+}
+
+/// Create a unified diff of two texts, which is empty if they are the same. With `color`, removed
+/// lines are red, added lines green and hunk headers cyan.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str, color: bool) -> String {
+    let edits = diff_lines(old, new);
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Equal(_)))
+        .map(|(ii, _)| ii)
+        .collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text
+        }
+    };
+
+    let mut ret = String::new();
+    ret.push_str(&paint("1", format!("--- {old_name}")));
+    ret.push('\n');
+    ret.push_str(&paint("1", format!("+++ {new_name}")));
+    ret.push('\n');
+
+    // Group changes, which are close enough to share their context, into hunks.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &change in &changes {
+        let start = change.saturating_sub(CONTEXT);
+        let end = (change + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        // Line numbers of the first line of the hunk in both texts.
+        let old_start = 1 + edits[..start]
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Insert(_)))
+            .count();
+        let new_start = 1 + edits[..start]
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Delete(_)))
+            .count();
+        let hunk = &edits[start..end];
+        let old_len = hunk
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Insert(_)))
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|edit| !matches!(edit, Edit::Delete(_)))
+            .count();
+
+        ret.push_str(&paint(
+            "36",
+            format!(
+                "@@ -{} +{} @@",
+                range(old_start, old_len),
+                range(new_start, new_len)
+            ),
+        ));
+        ret.push('\n');
+        for edit in hunk {
+            let (sign, code) = match edit {
+                Edit::Equal(_) => (' ', ""),
+                Edit::Delete(_) => ('-', "31"),
+                Edit::Insert(_) => ('+', "32"),
+            };
+            let line = edit.line();
+            let text = format!("{sign}{}", line.trim_end_matches(['\n', '\r']));
+            ret.push_str(&if code.is_empty() {
+                text
+            } else {
+                paint(code, text)
+            });
+            ret.push('\n');
+            if !line.ends_with('\n') {
+                ret.push_str("\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    ret
+}
+
+/// Merge the changes made to `base` in `local` and in `other`. Regions, which were changed
+/// differently in both, are conflicts and are written with both versions between conflict markers.
+/// Returns the merged text and the number of conflicts.
+pub fn merge(base: &str, local: &str, other: &str) -> (String, usize) {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let local_lines: Vec<&str> = local.split_inclusive('\n').collect();
+    let other_lines: Vec<&str> = other.split_inclusive('\n').collect();
+    let local_matches = matches(base, local, base_lines.len());
+    let other_matches = matches(base, other, base_lines.len());
+
+    let mut ret = String::new();
+    let mut conflicts = 0;
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    while i < base_lines.len() || a < local_lines.len() || b < other_lines.len() {
+        if i < base_lines.len() && local_matches[i] == Some(a) && other_matches[i] == Some(b) {
+            ret.push_str(base_lines[i]);
+            i += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+
+        // The changed region ends at the next base line, which is in both texts.
+        let (j, end_a, end_b) = (i..base_lines.len())
+            .find_map(|j| Some((j, local_matches[j]?, other_matches[j]?)))
+            .unwrap_or((base_lines.len(), local_lines.len(), other_lines.len()));
+        let base_part = &base_lines[i..j];
+        let local_part = &local_lines[a..end_a];
+        let other_part = &other_lines[b..end_b];
+        if local_part == base_part || local_part == other_part {
+            ret.extend(other_part.iter().copied());
+        } else if other_part == base_part {
+            ret.extend(local_part.iter().copied());
+        } else {
+            conflicts += 1;
+            for (marker, part) in [
+                ("<<<<<<< local", local_part),
+                ("||||||| last applied", base_part),
+                ("=======", other_part),
+            ] {
+                ret.push_str(marker);
+                ret.push('\n');
+                ret.extend(part.iter().copied());
+                if !ret.ends_with('\n') {
+                    ret.push('\n');
+                }
+            }
+            ret.push_str(">>>>>>> template\n");
+        }
+        (i, a, b) = (j, end_a, end_b);
+    }
+
+    (ret, conflicts)
+}
+
+/// For every line of `base`, the index of the same line in `other`, if it's kept in `other`.
+fn matches(base: &str, other: &str, len: usize) -> Vec<Option<usize>> {
+    let mut ret = vec![None; len];
+    let (mut i, mut j) = (0, 0);
+    for edit in diff_lines(base, other) {
+        match edit {
+            Edit::Equal(_) => {
+                ret[i] = Some(j);
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete(_) => i += 1,
+            Edit::Insert(_) => j += 1,
+        }
+    }
+
+    ret
+}
+
+/// Format a range of lines of a hunk header, which is just the start for a single line and
+/// points to the line before an empty range.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start - 1),
+        1 => start.to_string(),
+        _ => format!("{start},{len}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_lines, merge, unified, Edit};
+
+    #[test]
+    fn edits() {
+        assert_eq!(
+            diff_lines("a\nb\nc\n", "a\nx\nc\n"),
+            vec![
+                Edit::Equal("a\n"),
+                Edit::Delete("b\n"),
+                Edit::Insert("x\n"),
+                Edit::Equal("c\n")
+            ]
+        );
+        assert_eq!(diff_lines("", "a"), vec![Edit::Insert("a")]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn unified_diff() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n12\n13\n";
+
+        assert_eq!(
+            unified(old, new, "a", "b", false),
+            "--- a\n+++ b\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n@@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n"
+        );
+        assert_eq!(unified(old, old, "a", "b", false), "");
+        assert_eq!(
+            unified("", "new", "a", "b", false),
+            "--- a\n+++ b\n@@ -0,0 +1 @@\n+new\n\\ No newline at end of file\n"
+        );
+        assert_eq!(
+            unified("x\n", "x", "a", "b", false),
+            "--- a\n+++ b\n@@ -1 +1 @@\n-x\n+x\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn three_way_merge() {
+        let base = "a\nb\nc\nd\ne\n";
+        // Changes in different places merge cleanly.
+        assert_eq!(
+            merge(base, "a\nB\nc\nd\ne\n", "a\nb\nc\nd\nE\nf\n"),
+            ("a\nB\nc\nd\nE\nf\n".to_string(), 0)
+        );
+        // The same change on both sides is no conflict.
+        assert_eq!(
+            merge(base, "x\na\nb\nc\nd\ne\n", "x\na\nb\nc\nd\ne\n"),
+            ("x\na\nb\nc\nd\ne\n".to_string(), 0)
+        );
+        assert_eq!(
+            merge(base, "a\nlocal\nc\nd\ne\n", "a\nother\nc\nd\ne"),
+            (
+                "a\n<<<<<<< local\nlocal\n||||||| last applied\nb\n=======\nother\n>>>>>>> template\nc\nd\ne".to_string(),
+                1
+            )
+        );
+        assert_eq!(merge("", "local", "other").1, 1);
+    }
+}
@@ -2,6 +2,7 @@ use hp::errors::HpError;
 use std::env;
 use std::fmt;
 use std::io;
+use std::ops::Range;
 use std::string;
 
 /// Custom wrapper for `Result`.
@@ -19,11 +20,113 @@ pub enum Error {
     /// Error with a custom message.
     Custom(String),
     HpError(HpError),
+    /// Error in a derfile or a template file, pointing to the part of the file, which caused it.
+    Diagnostic(Diagnostic),
 }
 
-impl fmt::Display for Error {
+/// A message about a part of a file, rendered like a compiler error with the offending line and
+/// a caret under the offending part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Path of the file, empty if not known yet, see `Error::in_file`.
+    pub path: String,
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Columns of the offending part of the line, starting from 1.
+    pub columns: Range<usize>,
+    /// The offending line.
+    pub snippet: String,
+    pub message: String,
+    /// Additional lines printed below the snippet, like where a file was included from.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Create a diagnostic pointing to `span`, a range of bytes in `snippet`.
+    pub fn new(line: usize, snippet: &str, span: Range<usize>, message: impl Into<String>) -> Self {
+        let column = |index: usize| snippet[..index.min(snippet.len())].chars().count() + 1;
+        Self {
+            path: String::new(),
+            line,
+            columns: column(span.start)..column(span.end),
+            snippet: snippet.to_string(),
+            message: message.into(),
+            notes: Vec::new(),
+        }
+    }
+
+    /// Create a diagnostic pointing to `part` of `snippet`. If `part` is not a subslice of
+    /// `snippet`, the whole line is pointed to.
+    pub fn at(line: usize, snippet: &str, part: &str, message: impl Into<String>) -> Self {
+        let start = (part.as_ptr() as usize).wrapping_sub(snippet.as_ptr() as usize);
+        let span = if start <= snippet.len() && start + part.len() <= snippet.len() {
+            start..start + part.len()
+        } else {
+            let trimmed = snippet.trim_start();
+            let start = snippet.len() - trimmed.len();
+            start..start + trimmed.trim_end().len()
+        };
+        Self::new(line, snippet, span, message)
+    }
+
+    /// The diagnostic as `path:line:column: message`, followed by its notes, one per line.
+    pub fn plain(&self) -> String {
+        let mut ret = format!(
+            "{}:{}:{}: {}",
+            self.path, self.line, self.columns.start, self.message
+        );
+        for note in &self.notes {
+            ret.push_str(&format!("\n{note}"));
+        }
+
+        ret
+    }
+}
+
+impl fmt::Display for Diagnostic {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let formatted_string: String = match self {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Tabs are kept, so that the caret lines up with the snippet.
+        let indent: String = self
+            .snippet
+            .chars()
+            .take(self.columns.start - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat((self.columns.end - self.columns.start).max(1));
+
+        writeln!(fmt, "{}", self.message)?;
+        writeln!(
+            fmt,
+            "{gutter}--> {}:{}:{}",
+            self.path, self.line, self.columns.start
+        )?;
+        writeln!(fmt, "{gutter} |")?;
+        writeln!(fmt, "{} | {}", self.line, self.snippet)?;
+        write!(fmt, "{gutter} | {indent}\x1b[31m{carets}\x1b[0m")?;
+        for note in &self.notes {
+            write!(fmt, "\n{gutter} = {note}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error {
+    /// Set the path of the file a diagnostic points to, if it is not known yet.
+    pub fn in_file(self, path: &str) -> Self {
+        match self {
+            Self::Diagnostic(mut diagnostic) if diagnostic.path.is_empty() => {
+                diagnostic.path = path.to_string();
+                Self::Diagnostic(diagnostic)
+            }
+            other => other,
+        }
+    }
+
+    /// The error message without the `[ERROR]` prefix.
+    pub fn message(&self) -> String {
+        match self {
             Self::Io(e) => {
                 format!("Inner IO Error: {:?}", e.kind())
             }
@@ -39,8 +142,24 @@ impl fmt::Display for Error {
             Self::HpError(e) => {
                 format!("{e}")
             }
-        };
-        writeln!(fmt, "[\x1b[31mERROR\x1b[0m] {formatted_string}")
+            Self::Diagnostic(e) => {
+                format!("{e}")
+            }
+        }
+    }
+
+    /// The error message without any colours, and diagnostics on a single line, for hooks.
+    pub fn plain_message(&self) -> String {
+        match self {
+            Self::Diagnostic(e) => e.plain(),
+            other => other.message(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "[\x1b[31mERROR\x1b[0m] {}", self.message())
     }
 }
 
@@ -73,3 +192,31 @@ impl From<HpError> for Error {
         Self::HpError(e)
     }
 }
+
+impl From<Diagnostic> for Error {
+    fn from(other: Diagnostic) -> Self {
+        Self::Diagnostic(other)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Diagnostic, Error};
+
+    #[test]
+    fn plain_message() {
+        let line = "hostnames = $missing";
+        let mut diagnostic = Diagnostic::at(3, line, &line[12..], "Unknown variable: $missing");
+        diagnostic.path = "derfile".to_string();
+        diagnostic.notes.push("included from: other".to_string());
+        let error = Error::from(diagnostic);
+
+        assert!(error.message().contains('\x1b'));
+        assert_eq!(
+            error.plain_message(),
+            "derfile:3:13: Unknown variable: $missing\nincluded from: other"
+        );
+        let error = Error::from("Disk full".to_string());
+        assert_eq!(error.plain_message(), error.message());
+    }
+}
@@ -0,0 +1,232 @@
+//! Tokenizer of template files.
+//!
+//! Template files are line based, every line is either text, or a single block symbol or
+//! directive, optionally written behind a comment prefix. The tokenizer splits a template file
+//! into lines, keeping their original line endings, and classifies every line, so that the `ast`
+//! module can build a tree out of them and rendering can reproduce the text byte for byte.
+
+use crate::error::*;
+use crate::template::{
+    TEMP_ELSE, TEMP_END, TEMP_ESCAPE, TEMP_FOR, TEMP_FOR_END, TEMP_INCLUDE, TEMP_START,
+};
+
+/// A line of a template file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line<'a> {
+    /// Line number, starting from 1.
+    pub number: usize,
+    /// Contents of the line without its line ending.
+    pub text: &'a str,
+    /// The line ending, either `\n`, `\r\n`, or empty for the last line of a file without a final
+    /// newline.
+    pub ending: &'a str,
+}
+
+/// A classified line of a template file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub line: Line<'a>,
+    /// The line without surrounding whitespace and a comment prefix in front of a symbol or a
+    /// directive.
+    pub directive: &'a str,
+    pub kind: TokenKind<'a>,
+}
+
+/// What a line of a template file is. Headers and paths are subslices of the line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind<'a> {
+    /// Text, which is output as it is, apart from variable references.
+    Text,
+    /// Text with an escaped block symbol or directive, holding the byte index of the escaping `\`
+    /// in the line, which is left out of the output.
+    Escaped(usize),
+    /// `@@` with the block header after it.
+    BlockStart(&'a str),
+    /// `@:` with the block header after it, which is empty for the final branch.
+    Else(&'a str),
+    /// `@!`
+    End,
+    /// `@@include` with the path of the included file.
+    Include(&'a str),
+    /// `@@for` with the loop header after it.
+    For(&'a str),
+    /// `@@end`
+    EndFor,
+}
+
+impl Line<'_> {
+    /// Create an error pointing to `part` of this line.
+    pub fn error(&self, part: &str, message: impl Into<String>) -> Error {
+        Diagnostic::at(self.number, self.text, part, message).into()
+    }
+
+    /// Turn a custom error into one pointing to `part` of this line.
+    pub fn in_line(&self, error: Error, part: &str, context: &str) -> Error {
+        match error {
+            Error::Custom(message) => self.error(part, format!("{context}: {message}!")),
+            other => other,
+        }
+    }
+}
+
+/// Split the contents of a template file into classified lines.
+///
+/// Symbols and directives can be written behind any of the `comments` prefixes, but they always
+/// have to be the only thing on their line.
+pub fn tokenize<'a>(contents: &'a str, comments: &[String]) -> Vec<Token<'a>> {
+    contents
+        .split_inclusive('\n')
+        .enumerate()
+        .map(|(ii, raw)| {
+            let text = raw
+                .strip_suffix("\r\n")
+                .or_else(|| raw.strip_suffix('\n'))
+                .unwrap_or(raw);
+            let line = Line {
+                number: ii + 1,
+                text,
+                ending: &raw[text.len()..],
+            };
+            let directive = strip_comment(text, comments);
+            Token {
+                line,
+                directive,
+                kind: classify(text, directive),
+            }
+        })
+        .collect()
+}
+
+/// Directives start with `@@` just like block headers, so they never collide with content like
+/// SCSS's `@include` or Objective-C's `@end`. They have to be checked first.
+fn classify<'a>(text: &'a str, directive: &'a str) -> TokenKind<'a> {
+    if let Some(escaped) = strip_escape(text, directive) {
+        TokenKind::Escaped(escaped)
+    } else if let Some(include_path) = directive
+        .strip_prefix(TEMP_INCLUDE)
+        .filter(|rest| is_directive_header(rest))
+    {
+        TokenKind::Include(include_path.trim())
+    } else if let Some(header) = directive
+        .strip_prefix(TEMP_FOR)
+        .filter(|rest| is_directive_header(rest))
+    {
+        TokenKind::For(header)
+    } else if directive == TEMP_FOR_END {
+        TokenKind::EndFor
+    } else if let Some(header) = directive
+        .strip_prefix(TEMP_START)
+        .filter(|header| is_block_header(header))
+    {
+        TokenKind::BlockStart(header)
+    } else if let Some(header) = directive
+        .strip_prefix(TEMP_ELSE)
+        .filter(|header| is_block_header(header))
+    {
+        TokenKind::Else(header)
+    } else if directive == TEMP_END {
+        TokenKind::End
+    } else {
+        TokenKind::Text
+    }
+}
+
+/// Strip surrounding whitespace and a comment prefix from a line, if what's behind the comment
+/// prefix looks like a block symbol or a directive.
+fn strip_comment<'a>(line: &'a str, comments: &[String]) -> &'a str {
+    let trimmed = line.trim();
+    for comment in comments {
+        if let Some(rest) = trimmed.strip_prefix(comment.as_str()) {
+            let rest = rest.trim_start();
+            if rest.starts_with('@') || rest.starts_with(TEMP_ESCAPE) {
+                return rest;
+            }
+        }
+    }
+
+    trimmed
+}
+
+/// Check if the rest of a line after `@@` or `@:` can be a block header. Lines like git diff hunk
+/// headers (`@@ -1,3 +1,4 @@`) or `@@@` are text, as no list of hostnames or condition contains
+/// `@@` or starts with `@`.
+fn is_block_header(header: &str) -> bool {
+    !header.contains(TEMP_START) && !header.trim_start().starts_with('@')
+}
+
+/// Check if the rest of a line after `@@include` or `@@for` can be its path or loop header. It
+/// has to be separated by whitespace and, like a block header, can't contain `@@`. Unlike a block
+/// header, it may start with `@`, as in `@@include @{shell}.sh`.
+fn is_directive_header(header: &str) -> bool {
+    header.starts_with(char::is_whitespace) && !header.contains(TEMP_START)
+}
+
+/// Find an escaped block symbol or directive in a line, like `\@@` or `\@@include`, and return the
+/// byte index of the escaping `\` in `line`. `trimmed` is the line with its whitespace and comment
+/// prefix stripped, see `strip_comment`.
+///
+/// Only one `\` is removed, so `\\@@` is output as `\@@`.
+fn strip_escape(line: &str, trimmed: &str) -> Option<usize> {
+    let symbol = trimmed.trim_start_matches(TEMP_ESCAPE);
+    if symbol.len() == trimmed.len()
+        || ![
+            TEMP_START,
+            TEMP_ELSE,
+            TEMP_END,
+            TEMP_INCLUDE,
+            TEMP_FOR,
+            TEMP_FOR_END,
+        ]
+        .iter()
+        .any(|each| symbol.starts_with(each))
+    {
+        return None;
+    }
+
+    // `trimmed` is a subslice of `line`
+    Some(trimmed.as_ptr() as usize - line.as_ptr() as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tokenize, TokenKind};
+
+    #[test]
+    fn line_endings() {
+        let tokens = tokenize("a\r\n@@ laptop\nb", &[]);
+
+        assert_eq!(tokens.len(), 3);
+        assert_eq!((tokens[0].line.text, tokens[0].line.ending), ("a", "\r\n"));
+        assert_eq!(tokens[1].kind, TokenKind::BlockStart(" laptop"));
+        assert_eq!((tokens[2].line.text, tokens[2].line.ending), ("b", ""));
+        assert!(tokenize("", &[]).is_empty());
+    }
+
+    #[test]
+    fn directives() {
+        let kinds: Vec<TokenKind> = tokenize(
+            "# @: \n\\@!\n@@include a.t\n @@for x in a\n@@end \n@! x\n@@ -1 +1 @@",
+            &["#".to_string()],
+        )
+        .into_iter()
+        .map(|token| token.kind)
+        .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Else(""),
+                TokenKind::Escaped(0),
+                TokenKind::Include("a.t"),
+                TokenKind::For(" x in a"),
+                TokenKind::EndFor,
+                TokenKind::Text,
+                TokenKind::Text,
+            ]
+        );
+        assert_eq!(
+            tokenize("@@include @{shell}.t", &[])[0].kind,
+            TokenKind::Include("@{shell}.t")
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! der, a dotfile manager, which renders templates differently for each machine.
+//!
+//! The `der` binary is built on top of this library. Besides what the binary needs, the library
+//! exposes the `lexer` and the `ast` of template files, so tools like linters or previews can
+//! parse templates exactly the way der does:
+//!
+//! ```
+//! use der::ast::{parse, Node};
+//!
+//! let nodes = parse("a\n@@ laptop\nb\n@!\n", &[]).unwrap();
+//! assert!(matches!(nodes[1], Node::Block(_)));
+//! ```
+
+use std::cell::RefCell;
+
+pub mod apply;
+pub mod ast;
+pub mod backup;
+pub mod condition;
+pub mod config;
+pub mod derfile;
+mod diff;
+pub mod error;
+pub mod lexer;
+pub mod manifest;
+pub mod pattern;
+pub mod prune;
+pub mod template;
+pub mod utils;
+
+// Global variable for debugging
+thread_local! {pub static DEBUG: RefCell<bool> = const { RefCell::new(false) }}
@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::env;
 use std::fs;
 use std::path;
 use std::process::exit;
@@ -6,32 +6,33 @@ use std::process::exit;
 use hp::ParsedArguments;
 use hp::{Parser, Template as HpTemplate};
 
-mod config;
-mod derfile;
-mod error;
-mod template;
-mod utils;
-
-use config::*;
-use derfile::*;
-use error::*;
-use template::*;
-use utils::debug;
+use der::apply::{self, ApplyOptions};
+use der::config::*;
+use der::derfile::{self, *};
+use der::error::*;
+use der::utils::{self, debug, HOSTNAME_VAR};
+use der::{backup, prune, DEBUG};
 // use utils::execute_code;
 
-// Global variable for debugging
-thread_local! {static DEBUG: RefCell<bool> = RefCell::new(false)}
-
 /// Parse arguments and run the application.
 fn run(args: ParsedArguments) -> Result {
-    let mut derfile: Option<Derfile> = None;
-    let mut config: Config;
-    config = Config::load_default()?;
-
     if args.has("--debug") {
         DEBUG.with(|v| *v.borrow_mut() = true);
     }
 
+    // The hostname has to be set before any shell code of the config file or derfile runs.
+    if let Some(host_arg) = args.get("--host") {
+        let hostname = &host_arg.values()[0];
+        env::set_var(HOSTNAME_VAR, hostname);
+        if debug() {
+            println!("[\x1b[32mINFO\x1b[0m] Rendering for host: {hostname}")
+        }
+    }
+
+    let mut derfile: Option<Derfile> = None;
+    let mut config: Config;
+    config = Config::load_default()?;
+
     if let Some(config_arg) = args.get("-c") {
         let config_path = &config_arg.values()[0];
         if let Ok(conf) = Config::load(&config_path) {
@@ -44,6 +45,34 @@ fn run(args: ParsedArguments) -> Result {
         }
     }
 
+    if args.has("--backups") {
+        let root = backup::backups_dir()?;
+        for each in backup::list(&root)? {
+            println!("{} ({} file(s))", each.id, each.files.len());
+            for file in each.files {
+                println!("    {}", file.path.to_string_lossy());
+            }
+        }
+    }
+
+    if let Some(restore_arg) = args.get("--restore") {
+        let root = backup::backups_dir()?;
+        let values = restore_arg.values();
+        let Some(id) = values.first() else {
+            return Err("Missing the id of a backup to restore!".to_string().into());
+        };
+        let file = match values.get(1) {
+            Some(file) => Some(utils::normalize_path(&env::current_dir()?.join(file))),
+            None => None,
+        };
+        for restored in backup::restore(&root, id, file.as_deref())? {
+            println!(
+                "[\x1b[32mINFO\x1b[0m] Restored: {}",
+                restored.to_string_lossy()
+            );
+        }
+    }
+
     if let Some(derfile_arg) = args.get("-f") {
         // Get an absolute path to derfile.
         let open_derfile =
@@ -54,7 +83,7 @@ fn run(args: ParsedArguments) -> Result {
             &config,
         )?);
     }
-    if args.has("-a") {
+    if args.has("-a") || args.has("--dry-run") || args.has("status") || args.has("--prune") {
         // Apply template files according to derfile rules.
         let derfile_default_path = path::Path::new("./derfile").canonicalize();
 
@@ -72,23 +101,33 @@ fn run(args: ParsedArguments) -> Result {
             )?);
         }
 
-        let template_structures: Vec<Template> = derfile
-            .unwrap()
-            .templates
-            .values()
-            .map(Clone::clone)
-            .collect();
-        let vecs = recursive_build(template_structures)?;
-        for structure in vecs {
-            if let TemplateStructure::File(mut f) = structure {
-                if debug() {
-                    println!("[\x1b[32mINFO\x1b[0m] Applying: {}", f.0.path)
-                }
-                f.apply()?;
+        let options = ApplyOptions {
+            ignore_hosts: args.has("--ignore-hosts"),
+            dry_run: args.has("--dry-run"),
+            keep_backups: config.keep_backups,
+            transactional: args.has("--transactional"),
+            force: args.has("--force"),
+        };
+        if args.has("status") {
+            let statuses = apply::status(&derfile.unwrap(), &options)?;
+            if statuses.is_empty() {
+                println!("[\x1b[32mINFO\x1b[0m] No files were applied on this machine yet.");
+            }
+            for (entry, status) in statuses {
+                println!("{status}: {}", entry.target.to_string_lossy());
                 if debug() {
-                    println!("[\x1b[32mINFO\x1b[0m] Done!");
+                    println!("    from {}", entry.template);
                 }
             }
+        } else {
+            let derfile = derfile.unwrap();
+            if args.has("-a") || !args.has("--prune") {
+                let summary = apply::apply(&derfile, &options)?;
+                println!("{summary}");
+            }
+            if args.has("--prune") {
+                prune::prune(&derfile, &options, args.has("--yes"))?;
+            }
         }
     }
 
@@ -130,6 +169,60 @@ fn main() -> Result {
             .number_of_values(1)
             .optional_values(false),
     );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--dry-run")
+            .with_help("Show what applying a derfile would change, without writing anything."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--transactional")
+            .with_help("Write either all rendered files, or none of them if anything fails."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--force")
+            .with_help("Overwrite files, which were changed since der wrote them, without asking."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("status")
+            .with_help("Show whether the applied files are up-to-date, modified, missing, or out-of-date with their templates."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--prune")
+            .with_help("Remove applied files, which no template produces anymore."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--yes")
+            .with_help("Don't ask for confirmation."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--backups")
+            .with_help("List all backups of replaced files."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--restore")
+            .with_help("Restore all files of a backup, or a single one: --restore ID [FILE]")
+            .number_of_values(2)
+            .optional_values(true),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--ignore-hosts")
+            .with_help("Apply templates even if the current host is not in their hostnames."),
+    );
+    parser.add_template(
+        HpTemplate::new()
+            .matches("--host")
+            .with_help("Render templates for a different host than the current one.")
+            .number_of_values(1)
+            .optional_values(false),
+    );
 
     let result = parser.parse(None);
 
@@ -0,0 +1,425 @@
+//! The manifest of all files deployed on this machine.
+//!
+//! After every run of `der -a`, the manifest in der's state directory records each written target
+//! with the template and derfile it came from, a hash of its contents, its mode and when it was
+//! written. It's what lets `der status` tell whether a managed file is still the way der left it,
+//! and `der --prune` find files, which no template of their derfile produces anymore. Directories
+//! created by der are recorded too, so they can be removed once they're empty.
+//!
+//! A copy of every rendered file, which der writes, is kept in the `applied` directory next to the
+//! manifest. It's the common base for merging local edits of a target with a newer rendering.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::*;
+use crate::utils::{mirror_path, state_dir, write_atomic};
+
+/// A file deployed by der.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub target: PathBuf,
+    /// Path of the template file or directory, which produced the target.
+    pub template: String,
+    /// Path of the derfile of the template, `None` in manifests written before it was recorded.
+    pub derfile: Option<PathBuf>,
+    /// Hash of the contents of the target, see `hash`, `None` for linked directories.
+    pub hash: Option<u64>,
+    /// Hash of what the template rendered to. It differs from `hash`, if local changes of the
+    /// target were merged into the rendered template.
+    pub rendered: Option<u64>,
+    pub mode: u32,
+    /// When the target was written, in seconds since the UNIX epoch.
+    pub applied: u64,
+    /// Where the target points to, if it was deployed as a symlink.
+    pub link: Option<PathBuf>,
+}
+
+/// State of a managed file, as reported by `der status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The file is the way der wrote it, and its template still renders to it.
+    UpToDate,
+    /// The file was changed since der wrote it.
+    Modified,
+    /// The file was removed since der wrote it.
+    Missing,
+    /// The file is the way der wrote it, but its template renders to something else now.
+    OutOfDate,
+    /// No template produces the file anymore.
+    Orphaned,
+}
+
+/// All files deployed on this machine, by their targets.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<PathBuf, Entry>,
+    /// Directories, which der created to write targets to.
+    pub dirs: BTreeSet<PathBuf>,
+}
+
+/// Prefix of lines of the manifest, which record a directory.
+const DIR_PREF: &str = "dir\t";
+
+impl Manifest {
+    /// Path of the manifest in der's state directory.
+    pub fn path() -> Result<PathBuf> {
+        Ok(state_dir()?.join("manifest"))
+    }
+
+    /// Load a manifest, which is empty if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let mut ret = Self::default();
+        for (ii, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if let Some(dir) = line.strip_prefix(DIR_PREF) {
+                ret.dirs.insert(PathBuf::from(dir));
+                continue;
+            }
+            let entry = parse_line(line).ok_or_else(|| {
+                format!(
+                    "Invalid line {} in manifest: {}",
+                    ii + 1,
+                    path.to_string_lossy()
+                )
+            })?;
+            ret.entries.insert(entry.target.clone(), entry);
+        }
+
+        Ok(ret)
+    }
+
+    /// Write the manifest, one tab separated line per entry or directory.
+    pub fn save(&self, path: &Path) -> Result {
+        let mut contents = String::new();
+        for dir in &self.dirs {
+            contents.push_str(&format!("{DIR_PREF}{}\n", dir.to_string_lossy()));
+        }
+        for entry in self.entries.values() {
+            contents.push_str(&format!(
+                "{}\t{:o}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                format_hash(entry.hash),
+                entry.mode,
+                entry.applied,
+                entry.template,
+                entry
+                    .link
+                    .as_ref()
+                    .map(|link| link.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                entry
+                    .derfile
+                    .as_ref()
+                    .map(|derfile| derfile.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                format_hash(entry.rendered),
+                entry.target.to_string_lossy()
+            ));
+        }
+
+        write_atomic(path, contents.as_bytes(), None)
+    }
+
+    /// Record a target, which was just written with contents hashing to `hash`, while its template
+    /// rendered to contents hashing to `rendered`. Its mode is read from the file itself.
+    pub fn record(
+        &mut self,
+        target: &Path,
+        template: &str,
+        derfile: &Path,
+        hash: Option<u64>,
+        rendered: Option<u64>,
+    ) -> Result {
+        let link = fs::symlink_metadata(target)?
+            .file_type()
+            .is_symlink()
+            .then(|| fs::read_link(target))
+            .transpose()?;
+        let applied = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.entries.insert(
+            target.to_path_buf(),
+            Entry {
+                target: target.to_path_buf(),
+                template: template.to_string(),
+                derfile: Some(derfile.to_path_buf()),
+                hash,
+                rendered,
+                mode: fs::metadata(target)?.mode() & 0o7777,
+                applied,
+                link,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Record directories, which were created to write a target.
+    pub fn record_dirs(&mut self, dirs: Vec<PathBuf>) {
+        self.dirs.extend(dirs)
+    }
+
+    /// Remove all recorded directories, which are empty, the innermost ones first, and return
+    /// them. Directories, which don't exist anymore, are forgotten.
+    pub fn remove_empty_dirs(&mut self) -> Result<Vec<PathBuf>> {
+        let mut ret = Vec::new();
+        // Subdirectories come after their parents in the set.
+        for dir in self.dirs.clone().into_iter().rev() {
+            if !dir.is_dir() {
+                self.dirs.remove(&dir);
+            } else if fs::read_dir(&dir)?.next().is_none() {
+                fs::remove_dir(&dir)?;
+                self.dirs.remove(&dir);
+                ret.push(dir);
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+impl Entry {
+    /// Check if the target was applied from a derfile. Entries, which don't know their derfile,
+    /// belong to it if their template is in its directory.
+    pub fn belongs_to(&self, derfile: &Path) -> bool {
+        match &self.derfile {
+            Some(path) => path == derfile,
+            None => derfile
+                .parent()
+                .is_some_and(|dir| Path::new(&self.template).starts_with(dir)),
+        }
+    }
+
+    /// Check if the target is still the way der wrote it.
+    pub fn local_status(&self) -> Status {
+        let Ok(metadata) = fs::symlink_metadata(&self.target) else {
+            return Status::Missing;
+        };
+        if let Some(link) = &self.link {
+            if !metadata.file_type().is_symlink()
+                || fs::read_link(&self.target).ok().as_ref() != Some(link)
+            {
+                return Status::Modified;
+            }
+        }
+        // Follow links to what they point to.
+        let Ok(metadata) = fs::metadata(&self.target) else {
+            return Status::Missing;
+        };
+        if metadata.mode() & 0o7777 != self.mode {
+            return Status::Modified;
+        }
+        if let Some(expected) = self.hash {
+            match fs::read(&self.target) {
+                Ok(contents) if hash(&contents) == expected => (),
+                _ => return Status::Modified,
+            }
+        }
+
+        Status::UpToDate
+    }
+
+    /// Full status of the target, given the hash its template renders to now, or `None`, if no
+    /// template produces it anymore.
+    pub fn status(&self, rendered: Option<Option<u64>>) -> Status {
+        match (self.local_status(), rendered) {
+            (_, None) => Status::Orphaned,
+            (Status::UpToDate, Some(hash)) if hash != self.rendered => Status::OutOfDate,
+            (status, _) => status,
+        }
+    }
+}
+
+/// Path of the copy of what der last wrote to a target, e.g.
+/// `~/.local/state/der/applied/home/user/.bashrc`.
+pub fn applied_path(target: &Path) -> Result<PathBuf> {
+    Ok(mirror_path(&state_dir()?.join("applied"), target))
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    // Lines written before the derfile, and the hash of the rendered template, were recorded have
+    // fewer fields.
+    let fields = match line.matches('\t').count() {
+        5 => 6,
+        6 => 7,
+        _ => 8,
+    };
+    let mut split = line.splitn(fields, '\t');
+    let hash = parse_hash(split.next()?)?;
+    let mode = u32::from_str_radix(split.next()?, 8).ok()?;
+    let applied = split.next()?.parse().ok()?;
+    let template = split.next()?.to_string();
+    let link = match split.next()? {
+        "" => None,
+        link => Some(PathBuf::from(link)),
+    };
+    let derfile = match fields {
+        6 => None,
+        _ => match split.next()? {
+            "" => None,
+            derfile => Some(PathBuf::from(derfile)),
+        },
+    };
+    let rendered = match fields {
+        8 => parse_hash(split.next()?)?,
+        _ => hash,
+    };
+
+    Some(Entry {
+        target: PathBuf::from(split.next()?),
+        template,
+        derfile,
+        hash,
+        rendered,
+        mode,
+        applied,
+        link,
+    })
+}
+
+/// A hash in the manifest, in hex, or empty if there is none.
+fn format_hash(hash: Option<u64>) -> String {
+    hash.map(|hash| format!("{hash:016x}")).unwrap_or_default()
+}
+
+/// Parse a hash written by `format_hash`. Returns `None` if it's invalid.
+fn parse_hash(hash: &str) -> Option<Option<u64>> {
+    match hash {
+        "" => Some(None),
+        hash => Some(Some(u64::from_str_radix(hash, 16).ok()?)),
+    }
+}
+
+/// 64 bit FNV-1a hash of some contents. It's not cryptographic, but good enough to notice changes.
+pub fn hash(contents: &[u8]) -> u64 {
+    contents.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UpToDate => write!(f, "\x1b[32mup-to-date\x1b[0m"),
+            Self::Modified => write!(f, "\x1b[33mmodified locally\x1b[0m"),
+            Self::Missing => write!(f, "\x1b[31mmissing\x1b[0m"),
+            Self::OutOfDate => write!(f, "\x1b[36mout-of-date\x1b[0m"),
+            Self::Orphaned => write!(f, "\x1b[35morphaned\x1b[0m"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, parse_line, Manifest, Status};
+    use crate::utils::TempDir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn fnv() {
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn record_and_check() {
+        let dir = TempDir::new("manifest");
+        let target = dir.join("config");
+        fs::write(&target, "contents").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let derfile = dir.join("derfile");
+        let mut manifest = Manifest::default();
+        manifest
+            .record(
+                &target,
+                "config.t",
+                &derfile,
+                Some(hash(b"contents")),
+                Some(hash(b"contents")),
+            )
+            .unwrap();
+        manifest.save(&dir.join("manifest")).unwrap();
+        let manifest = Manifest::load(&dir.join("manifest")).unwrap();
+        let entry = &manifest.entries[&target];
+        assert_eq!(entry.template, "config.t");
+        assert_eq!(entry.rendered, Some(hash(b"contents")));
+        assert_eq!(entry.derfile, Some(derfile.clone()));
+        assert!(entry.belongs_to(&derfile));
+        assert!(!entry.belongs_to(&dir.join("other/derfile")));
+        assert_eq!(entry.mode, 0o640);
+        assert_eq!(entry.link, None);
+
+        let rendered = Some(Some(hash(b"contents")));
+        assert_eq!(entry.status(rendered), Status::UpToDate);
+        assert_eq!(entry.status(Some(Some(hash(b"new")))), Status::OutOfDate);
+        assert_eq!(entry.status(None), Status::Orphaned);
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(entry.status(rendered), Status::Modified);
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::write(&target, "edited").unwrap();
+        assert_eq!(entry.status(rendered), Status::Modified);
+        fs::remove_file(&target).unwrap();
+        assert_eq!(entry.status(rendered), Status::Missing);
+    }
+
+    #[test]
+    fn without_derfile() {
+        let entry = parse_line("\t644\t0\t/home/user/dots/vimrc.t\t\t/home/user/.vimrc").unwrap();
+        assert_eq!(entry.derfile, None);
+        assert_eq!(entry.rendered, entry.hash);
+        assert_eq!(entry.target, Path::new("/home/user/.vimrc"));
+        assert!(entry.belongs_to(Path::new("/home/user/dots/derfile")));
+        assert!(!entry.belongs_to(Path::new("/home/user/work/derfile")));
+
+        let entry =
+            parse_line("\t644\t0\tvimrc.t\t\t/home/user/dots/derfile\t/home/user/.vimrc").unwrap();
+        assert_eq!(
+            entry.derfile,
+            Some(PathBuf::from("/home/user/dots/derfile"))
+        );
+        assert_eq!(entry.target, Path::new("/home/user/.vimrc"));
+    }
+
+    #[test]
+    fn empty_dirs() {
+        let dir = TempDir::new("dirs");
+        fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        fs::create_dir_all(dir.join("a/full")).unwrap();
+        fs::write(dir.join("a/full/file"), "").unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.record_dirs(vec![
+            dir.join("a"),
+            dir.join("a/b"),
+            dir.join("a/b/c"),
+            dir.join("a/full"),
+            dir.join("gone"),
+        ]);
+        manifest.save(&dir.join("manifest")).unwrap();
+        let mut manifest = Manifest::load(&dir.join("manifest")).unwrap();
+        assert_eq!(manifest.dirs.len(), 5);
+
+        assert_eq!(
+            manifest.remove_empty_dirs().unwrap(),
+            vec![dir.join("a/b/c"), dir.join("a/b")]
+        );
+        assert!(dir.join("a/full").exists());
+        assert_eq!(
+            manifest.dirs.into_iter().collect::<Vec<_>>(),
+            vec![dir.join("a"), dir.join("a/full")]
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Hostname patterns. Everywhere a list of hostnames is accepted, each entry can either be an
+//! exact hostname, a shell-style glob like `*-laptop` or `build-[0-9][0-9]`, or a regular
+//! expression prefixed with `re:`, like `re:^build-\d+$`.
+//!
+//! One of der's goals is to do without dependencies apart from its argument parser `hp` (see
+//! "Why?" in the README), and hostnames are short, so instead of pulling in the `regex` crate,
+//! regular expressions are matched by a small engine. It supports the commonly used subset of the
+//! syntax: `.`, `^`, `$`, character classes (`[a-z]`, `[^0-9]`, `\d`, `\w`, `\s` and their
+//! negations), groups with alternation (`(a|b)`) and the `*`, `+`, `?` and `{n,m}` quantifiers.
+//! Globs are translated into the same representation, so they get the same matcher.
+
+use std::collections::BTreeSet;
+
+use crate::error::*;
+
+/// Prefix of a hostname pattern, which is a regular expression.
+pub const REGEX_PREF: &str = "re:";
+
+/// A compiled hostname pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// Sequence of nodes the whole pattern consists of.
+    nodes: Vec<Node>,
+    /// Whether the pattern has to match the whole input, or just some part of it.
+    anchored: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    /// Set of characters, possibly negated.
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    /// Alternatives, each of which is a sequence of nodes.
+    Group(Vec<Vec<Node>>),
+    /// A node repeated at least `min` and at most `max` times.
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    Digit,
+    Word,
+    Space,
+}
+
+impl Pattern {
+    /// Compile a single entry of a hostname list.
+    pub fn new(pattern: &str) -> Result<Self> {
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREF) {
+            let chars: Vec<char> = regex.chars().collect();
+            let mut parser = RegexParser {
+                chars: &chars,
+                index: 0,
+            };
+            let alternatives = parser.alternatives()?;
+            if parser.index != chars.len() {
+                return Err(format!("Invalid regular expression `{regex}`: unmatched `)`").into());
+            }
+            Ok(Self {
+                nodes: vec![Node::Group(alternatives)],
+                anchored: false,
+            })
+        } else {
+            Ok(Self {
+                nodes: glob(pattern),
+                anchored: true,
+            })
+        }
+    }
+
+    /// Whether this pattern matches `input`.
+    pub fn is_match(&self, input: &str) -> bool {
+        let chars: Vec<char> = input.chars().collect();
+        let starts: Vec<usize> = if self.anchored {
+            vec![0]
+        } else {
+            (0..=chars.len()).collect()
+        };
+
+        starts.into_iter().any(|start| {
+            let ends = match_sequence(&self.nodes, &chars, BTreeSet::from([start]));
+            if self.anchored {
+                ends.contains(&chars.len())
+            } else {
+                !ends.is_empty()
+            }
+        })
+    }
+}
+
+/// Find the first entry of a list of hostname patterns, which matches `hostname`.
+pub fn match_hostname<'a, S: AsRef<str>>(
+    patterns: &'a [S],
+    hostname: &str,
+) -> Result<Option<&'a str>> {
+    for pattern in patterns {
+        if Pattern::new(pattern.as_ref())?.is_match(hostname) {
+            return Ok(Some(pattern.as_ref()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Split a comma separated list of hostname patterns. Commas inside of brackets, parentheses or
+/// braces of a regular expression do not split the list.
+pub fn split_patterns(list: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut escaped = false;
+
+    for c in list.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' | '(' | '{' => depth += 1,
+            ']' | ')' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                ret.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    ret.push(current.trim().to_string());
+
+    ret
+}
+
+/// Translate a glob into pattern nodes. A glob without any special characters matches only the
+/// exact string.
+fn glob(pattern: &str) -> Vec<Node> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut ret = Vec::new();
+    let mut ii = 0;
+
+    while ii < chars.len() {
+        match chars[ii] {
+            '*' => ret.push(Node::Repeat(Box::new(Node::Any), 0, None)),
+            '?' => ret.push(Node::Any),
+            '[' => {
+                let mut parser = RegexParser {
+                    chars: &chars,
+                    index: ii + 1,
+                };
+                if let Ok(class) = parser.class('!') {
+                    ret.push(class);
+                    ii = parser.index;
+                    continue;
+                }
+                ret.push(Node::Char('['));
+            }
+            c => ret.push(Node::Char(c)),
+        }
+        ii += 1;
+    }
+
+    ret
+}
+
+struct RegexParser<'a> {
+    chars: &'a [char],
+    index: usize,
+}
+
+impl RegexParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        Err(format!(
+            "Invalid regular expression `{}`: {message}",
+            self.chars.iter().collect::<String>()
+        )
+        .into())
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+        let mut ret = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.index += 1;
+            ret.push(self.sequence()?);
+        }
+        Ok(ret)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>> {
+        let mut ret = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            ret.push(self.quantifier(atom)?);
+        }
+        Ok(ret)
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let c = self.peek().unwrap_or_default();
+        self.index += 1;
+
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '[' => self.class('^')?,
+            '(' => {
+                // Non capturing groups are the same as any other group here.
+                if self.chars[self.index..].starts_with(&['?', ':']) {
+                    self.index += 2;
+                }
+                let alternatives = self.alternatives()?;
+                if self.peek() != Some(')') {
+                    return self.error("unclosed `(`");
+                }
+                self.index += 1;
+                Node::Group(alternatives)
+            }
+            '\\' => match self.escape()? {
+                Some(item) => Node::Class(vec![item.0], item.1),
+                None => Node::Char(self.chars[self.index - 1]),
+            },
+            '*' | '+' | '?' | '{' => return self.error(&format!("nothing to repeat before `{c}`")),
+            c => Node::Char(c),
+        })
+    }
+
+    /// Parse an escape sequence after a `\`. Returns a class item and whether it is negated for
+    /// escapes of character classes, `None` for escaped literal characters.
+    fn escape(&mut self) -> Result<Option<(ClassItem, bool)>> {
+        let Some(c) = self.peek() else {
+            return self.error("trailing `\\`");
+        };
+        self.index += 1;
+
+        Ok(match c {
+            'd' => Some((ClassItem::Digit, false)),
+            'D' => Some((ClassItem::Digit, true)),
+            'w' => Some((ClassItem::Word, false)),
+            'W' => Some((ClassItem::Word, true)),
+            's' => Some((ClassItem::Space, false)),
+            'S' => Some((ClassItem::Space, true)),
+            _ => None,
+        })
+    }
+
+    /// Parse a character class, after its opening `[`. `negation` is the character, which negates
+    /// the class when placed first.
+    fn class(&mut self, negation: char) -> Result<Node> {
+        let mut items = Vec::new();
+        let negated = self.peek() == Some(negation);
+        if negated {
+            self.index += 1;
+        }
+
+        let mut first = true;
+        loop {
+            let Some(c) = self.peek() else {
+                return self.error("unclosed `[`");
+            };
+            self.index += 1;
+
+            let start = match c {
+                ']' if !first => break,
+                '\\' => match self.escape()? {
+                    // Negated escapes can't be combined inside of a class, so they are not allowed.
+                    Some((_, true)) => return self.error("negated escape inside of `[]`"),
+                    Some((item, false)) => {
+                        items.push(item);
+                        first = false;
+                        continue;
+                    }
+                    None => self.chars[self.index - 1],
+                },
+                c => c,
+            };
+            first = false;
+
+            if self.peek() == Some('-') && self.chars.get(self.index + 1).is_some_and(|c| *c != ']')
+            {
+                let end = self.chars[self.index + 1];
+                self.index += 2;
+                items.push(ClassItem::Range(start, end));
+            } else {
+                items.push(ClassItem::Range(start, start));
+            }
+        }
+
+        Ok(Node::Class(items, negated))
+    }
+
+    fn quantifier(&mut self, atom: Node) -> Result<Node> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let Some(length) = self.chars[self.index..].iter().position(|c| *c == '}') else {
+                    return self.error("unclosed `{`");
+                };
+                let counts: String = self.chars[self.index + 1..self.index + length]
+                    .iter()
+                    .collect();
+                let parse = |count: &str| count.trim().parse::<usize>().ok();
+                let (min, max) = match counts.split_once(',') {
+                    Some((min, "")) => (parse(min), None),
+                    Some((min, max)) => match parse(max) {
+                        Some(max) => (parse(min), Some(max)),
+                        None => return self.error("invalid `{}` quantifier"),
+                    },
+                    None => (parse(&counts), parse(&counts)),
+                };
+                let Some(min) = min else {
+                    return self.error("invalid `{}` quantifier");
+                };
+                self.index += length;
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.index += 1;
+        // Lazy quantifiers match the same set of strings.
+        if self.peek() == Some('?') {
+            self.index += 1;
+        }
+
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+}
+
+/// Match a sequence of nodes starting at each of the positions in `starts` and return all the
+/// positions, where a match can end. Tracking sets of positions instead of backtracking means
+/// every step matches its node once per reachable position, however many ways led there, so
+/// patterns like `(a*)*b` can't take exponential time. Matching stays polynomial in the lengths of
+/// the pattern and the input.
+fn match_sequence(nodes: &[Node], input: &[char], starts: BTreeSet<usize>) -> BTreeSet<usize> {
+    let mut positions = starts;
+    for node in nodes {
+        if positions.is_empty() {
+            break;
+        }
+        positions = positions
+            .into_iter()
+            .flat_map(|position| match_node(node, input, position))
+            .collect();
+    }
+    positions
+}
+
+fn match_node(node: &Node, input: &[char], position: usize) -> BTreeSet<usize> {
+    let current = input.get(position);
+    match node {
+        Node::Char(c) => single(current == Some(c), position),
+        Node::Any => single(current.is_some(), position),
+        Node::Class(items, negated) => single(
+            current.is_some_and(|c| items.iter().any(|item| item.contains(*c)) != *negated),
+            position,
+        ),
+        Node::Start => zero_width(position == 0, position),
+        Node::End => zero_width(position == input.len(), position),
+        Node::Group(alternatives) => alternatives
+            .iter()
+            .flat_map(|sequence| match_sequence(sequence, input, BTreeSet::from([position])))
+            .collect(),
+        Node::Repeat(node, min, max) => {
+            let mut ret = BTreeSet::new();
+            let mut current = BTreeSet::from([position]);
+            let mut count = 0;
+            loop {
+                if count >= *min {
+                    ret.extend(current.iter().copied());
+                }
+                if max.is_some_and(|max| count >= max) || current.is_empty() {
+                    break;
+                }
+                let next: BTreeSet<usize> = current
+                    .iter()
+                    .flat_map(|position| match_node(node, input, *position))
+                    .collect();
+                // Once repeating doesn't reach any new positions, it never will.
+                if count >= *min && next.is_subset(&ret) {
+                    break;
+                }
+                current = next;
+                count += 1;
+            }
+            ret
+        }
+    }
+}
+
+fn single(matches: bool, position: usize) -> BTreeSet<usize> {
+    if matches {
+        BTreeSet::from([position + 1])
+    } else {
+        BTreeSet::new()
+    }
+}
+
+fn zero_width(matches: bool, position: usize) -> BTreeSet<usize> {
+    if matches {
+        BTreeSet::from([position])
+    } else {
+        BTreeSet::new()
+    }
+}
+
+impl ClassItem {
+    fn contains(&self, c: char) -> bool {
+        match self {
+            Self::Range(start, end) => (*start..=*end).contains(&c),
+            Self::Digit => c.is_ascii_digit(),
+            Self::Word => c.is_alphanumeric() || c == '_',
+            Self::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_match(pattern: &str, input: &str) -> bool {
+        Pattern::new(pattern).unwrap().is_match(input)
+    }
+
+    #[test]
+    fn test_exact() {
+        assert!(is_match("laptop", "laptop"));
+        assert!(!is_match("laptop", "laptop2"));
+        assert!(!is_match("laptop", "my-laptop"));
+    }
+
+    #[test]
+    fn test_globs() {
+        assert!(is_match("*-laptop", "work-laptop"));
+        assert!(!is_match("*-laptop", "work-laptop-2"));
+        assert!(is_match("build-??", "build-01"));
+        assert!(!is_match("build-[0-3][0-9]", "build-40"));
+        assert!(is_match("build-[0-4][0-9]", "build-40"));
+        assert!(is_match("host[!ab]", "hostc"));
+        assert!(!is_match("host[!ab]", "hosta"));
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(is_match(r"re:^build-\d+$", "build-01"));
+        assert!(!is_match(r"re:^build-\d+$", "build-"));
+        assert!(!is_match(r"re:^build-\d+$", "build-01a"));
+        assert!(is_match(r"re:laptop", "my-laptop-2"));
+        assert!(is_match(r"re:^(gpu|cpu)-box\d{2,3}$", "gpu-box123"));
+        assert!(!is_match(r"re:^(gpu|cpu)-box\d{2,3}$", "gpu-box1"));
+        assert!(is_match(r"re:^[a-c]+\.local$", "abcab.local"));
+        assert!(!is_match(r"re:^[a-c]+\.local$", "abcabxlocal"));
+        assert!(!is_match(
+            r"re:^(a*)*$",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab"
+        ));
+        assert!(is_match(r"re:^x?y[^z]\w\s\S$", "yq_ a"));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(Pattern::new("re:(a").is_err());
+        assert!(Pattern::new("re:a)").is_err());
+        assert!(Pattern::new("re:[a").is_err());
+        assert!(Pattern::new("re:*a").is_err());
+        assert!(Pattern::new("re:a{x}").is_err());
+    }
+
+    #[test]
+    fn test_match_hostname() {
+        let patterns = ["desktop", "*-laptop", r"re:^build-\d+$"];
+        assert_eq!(
+            match_hostname(&patterns, "build-7").unwrap(),
+            Some(r"re:^build-\d+$")
+        );
+        assert_eq!(match_hostname(&patterns, "server").unwrap(), None);
+    }
+
+    #[test]
+    fn test_split_patterns() {
+        assert_eq!(
+            split_patterns(r"a, re:^b{1,2}$, c[0,1]"),
+            vec!["a", r"re:^b{1,2}$", "c[0,1]"]
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Pruning of orphaned files, which der applied in the past, but which no template produces
+//! anymore, because their template was removed from the derfile, or the file from its template
+//! directory. They are found with the manifest of applied files. Only files applied from the same
+//! derfile are considered, and files of templates for other hosts aren't orphaned.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::apply::{self, ApplyOptions};
+use crate::backup::{self, Run};
+use crate::derfile::Derfile;
+use crate::error::*;
+use crate::manifest::{self, Entry, Manifest, Status};
+use crate::utils::{ask, debug, is_symlink};
+
+/// Remove all orphaned files, after asking for confirmation, unless `yes` is set. Files are backed
+/// up before they are removed, unless backups are turned off, links are just removed. Empty
+/// directories created by der are removed too.
+pub fn prune(derfile: &Derfile, options: &ApplyOptions, yes: bool) -> Result {
+    let manifest_path = Manifest::path()?;
+    let mut manifest = Manifest::load(&manifest_path)?;
+    let orphans = orphans(&manifest, &derfile.path, &apply::targets(derfile)?);
+
+    if orphans.is_empty() {
+        println!("[\x1b[32mINFO\x1b[0m] There are no orphaned files.");
+        return Ok(());
+    }
+    for entry in &orphans {
+        print!(
+            "{}: {} (from {})",
+            Status::Orphaned,
+            entry.target.to_string_lossy(),
+            entry.template
+        );
+        match entry.local_status() {
+            Status::UpToDate => println!(),
+            status => println!(", {status}"),
+        }
+    }
+
+    if options.dry_run {
+        println!(
+            "[\x1b[32mINFO\x1b[0m] Would remove {} orphaned file(s).",
+            orphans.len()
+        );
+        return Ok(());
+    }
+    if !yes && !confirm(&format!("Remove {} orphaned file(s)?", orphans.len()))? {
+        println!("[\x1b[32mINFO\x1b[0m] Nothing was removed.");
+        return Ok(());
+    }
+
+    let backups = match options.keep_backups {
+        0 => None,
+        _ => Some(backup::backups_dir()?),
+    };
+    let mut run = backups.as_ref().map(|root| Run::new(root));
+    let mut backed_up = false;
+    for entry in &orphans {
+        backed_up |= remove(entry, &mut run)?;
+        manifest.entries.remove(&entry.target);
+    }
+    let dirs = manifest.remove_empty_dirs();
+    // Removed files are not in the manifest anymore, even if removing some directory failed.
+    manifest.save(&manifest_path)?;
+    for dir in dirs? {
+        if debug() {
+            println!(
+                "[\x1b[32mINFO\x1b[0m] Removed empty directory: {}",
+                dir.to_string_lossy()
+            );
+        }
+    }
+
+    println!(
+        "[\x1b[32mINFO\x1b[0m] Removed {} orphaned file(s).",
+        orphans.len()
+    );
+    if let (true, Some(run), Some(root)) = (backed_up, &run, &backups) {
+        println!(
+            "[\x1b[32mINFO\x1b[0m] Removed files were backed up, restore them with: der --restore {}",
+            run.id
+        );
+        backup::prune(root, options.keep_backups)?;
+    }
+
+    Ok(())
+}
+
+/// Entries of the manifest, which were applied from a derfile, but which none of its templates
+/// produce anymore. Files of other derfiles, and of templates for other hosts, are left alone.
+fn orphans(manifest: &Manifest, derfile: &Path, targets: &HashSet<PathBuf>) -> Vec<Entry> {
+    manifest
+        .entries
+        .values()
+        .filter(|entry| entry.belongs_to(derfile) && !targets.contains(&entry.target))
+        .cloned()
+        .collect()
+}
+
+/// Remove an orphaned file and return whether it was backed up. Files rendered to the build
+/// directory for a link, and the copy of what der last wrote, are removed with it.
+fn remove(entry: &Entry, run: &mut Option<Run>) -> Result<bool> {
+    let target = &entry.target;
+    let mut backed_up = false;
+    if is_symlink(target) {
+        fs::remove_file(target)?;
+    } else if target.is_file() {
+        if let Some(run) = run {
+            run.save(target)?;
+            backed_up = true;
+        }
+        fs::remove_file(target)?;
+    } else if target.exists() {
+        eprintln!(
+            "[\x1b[33mWARN\x1b[0m] Not removing {}, it's not a file anymore.",
+            target.to_string_lossy()
+        );
+    }
+
+    if let Some(link) = &entry.link {
+        if link.starts_with(apply::build_dir()?) && link.is_file() {
+            fs::remove_file(link)?;
+        }
+    }
+    let applied = manifest::applied_path(target)?;
+    if applied.is_file() {
+        fs::remove_file(applied)?;
+    }
+    if debug() {
+        println!(
+            "[\x1b[32mINFO\x1b[0m] Removed orphaned file: {}",
+            target.to_string_lossy()
+        );
+    }
+
+    Ok(backed_up)
+}
+
+/// Ask a yes or no question, no being the default.
+pub fn confirm(question: &str) -> Result<bool> {
+    Ok(matches!(
+        ask(&format!("{question} [y/N]"))?.as_str(),
+        "y" | "Y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{orphans, remove};
+    use crate::backup::{list, Run};
+    use crate::manifest::Manifest;
+    use crate::utils::TempDir;
+    use std::collections::HashSet;
+    use std::fs;
+
+    #[test]
+    fn remove_orphans() {
+        let dir = TempDir::new("prune");
+        let file = dir.join("file");
+        let link = dir.join("link");
+        fs::write(&file, "contents").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let derfile = dir.join("derfile");
+        let mut manifest = Manifest::default();
+        manifest
+            .record(&file, "file.t", &derfile, None, None)
+            .unwrap();
+        manifest
+            .record(&link, "link.t", &derfile, None, None)
+            .unwrap();
+
+        let root = dir.join("backups");
+        let mut run = Some(Run::new(&root));
+        assert!(!remove(&manifest.entries[&link], &mut run).unwrap());
+        assert!(file.exists());
+        assert!(remove(&manifest.entries[&file], &mut run).unwrap());
+        assert!(!file.exists());
+        assert_eq!(list(&root).unwrap()[0].files[0].path, file);
+        // Removing what's gone already is fine.
+        assert!(!remove(&manifest.entries[&file], &mut run).unwrap());
+    }
+
+    #[test]
+    fn only_orphans_of_the_derfile() {
+        let dir = TempDir::new("orphans");
+        let derfile = dir.join("dots/derfile");
+        let [kept, orphan, other, old] = dir.files(["kept", "orphan", "other", "old"]);
+
+        let mut manifest = Manifest::default();
+        manifest
+            .record(&kept, "kept.t", &derfile, None, None)
+            .unwrap();
+        manifest
+            .record(&orphan, "orphan.t", &derfile, None, None)
+            .unwrap();
+        let other_derfile = dir.join("work/derfile");
+        manifest
+            .record(&other, "other.t", &other_derfile, None, None)
+            .unwrap();
+        let template = dir.join("dots/old.t").to_string_lossy().to_string();
+        manifest
+            .record(&old, &template, &derfile, None, None)
+            .unwrap();
+        // Recorded before der recorded the derfile.
+        manifest.entries.get_mut(&old).unwrap().derfile = None;
+
+        let targets = HashSet::from([kept.clone()]);
+        let mut found: Vec<_> = orphans(&manifest, &derfile, &targets)
+            .into_iter()
+            .map(|entry| entry.target)
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![old, orphan]);
+        assert!(orphans(&manifest, &other_derfile, &HashSet::from([other])).is_empty());
+    }
+}
@@ -1,24 +1,43 @@
+//! After derfile parsing is done, and the `-a` flag is passed, a list of all
+//! `TemplateStrucutre`s will be created. These all basically boil down to a list of
+//! `TemplateFile`s, which are then applied, so according to specifications from the derfile, these
+//! template files will all be placed onto their apply paths, as specified in the derfile.
+//!
+//! The whole process starts with going over the list of all `Template`s from a derfile and then
+//! creating template structures from them. So if a template specifies a `TemplateFile` a
+//! `TemplateStructure::File` is constructed and placed into the `TemplateStrucutre`s vector and if
+//! a directory is encountered a recursive process will take place, where each directory is parsed
+//! into `TemplateStructure::Directory` and all its children are stored inside it as a list of
+//! `TemplateFile`s.
+
+use crate::ast::{self, Node};
+use crate::condition::Facts;
 use crate::derfile;
 use crate::error::*;
+use crate::lexer::Line;
+use crate::pattern::{match_hostname, Pattern};
 use crate::utils::*;
+use std::collections::HashMap;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path;
 
-/// After derfile parsing is done, and the `-a` flag is passed, a list of all
-/// `TemplateStrucutre`s will be created. These all basically boil down to a list of
-/// `TemplateFile`s, which are then applied, so according to specifications from the derfile, these
-/// template files will all be placed onto their apply paths, as specified in the derfile.
-///
-/// The whole process starts with going over the list of all `Template`s from a derfile and then
-/// creating template structures from them. So if a template specifies a `TemplateFile` a
-/// `TemplateStructure::File` is constructed and placed into the `TemplateStrucutre`s vector and if
-/// a directory is encountered a recursive process will take place, where each directory is parsed
-/// into `TemplateStructure::Directory` and all its children are stored inside it as a list of
-/// `TemplateFile`s.
-
-/// Begin and end code block symbols, these CAN be changed before compilation.
+/// Begin, else and end code block symbols, these CAN be changed before compilation.
 pub const TEMP_START: &str = "@@";
+pub const TEMP_ELSE: &str = "@:";
 pub const TEMP_END: &str = "@!";
+/// Prefix of a block header, which negates its list of hostnames.
+pub const TEMP_NOT: &str = "!";
+/// Start and end of a variable reference inside of a template file, e.g. `@{font_size}`.
+pub const TEMP_VAR_START: &str = "@{";
+pub const TEMP_VAR_END: &str = "}";
+/// Directive, which includes another template file, e.g. `@@include fragments/aliases.sh`.
+pub const TEMP_INCLUDE: &str = "@@include";
+/// Begin and end of a loop over the values of a list, e.g. `@@for host in $hosts`.
+pub const TEMP_FOR: &str = "@@for";
+pub const TEMP_FOR_END: &str = "@@end";
+/// Symbol, which placed in front of a variable reference outputs it literally.
+pub const TEMP_ESCAPE: &str = "\\";
 
 /// This type alias represents a vector of `TemplateStructure`s.
 pub type TemplateStructures = Vec<TemplateStructure>;
@@ -44,6 +63,27 @@ pub struct TemplateSettings {
     pub extensions: Vec<String>,
     /// Should parse files recursively in all its subdirectories?
     pub recursive: bool,
+    /// Derfile and config variables, which can be referenced from within the template file.
+    pub vars: HashMap<String, derfile::Variable>,
+    /// Host groups, which can be used in block headers.
+    pub groups: derfile::Groups,
+    /// Comment prefixes, behind which block symbols and directives can be written.
+    pub comments: Vec<String>,
+    /// Mode of the written file, see `derfile::Template::mode`.
+    pub mode: Option<u32>,
+    /// Owner of the written file.
+    pub owner: Option<String>,
+    /// Group of the written file.
+    pub group: Option<String>,
+    /// Modes of files in this directory matching a glob.
+    pub mode_rules: Vec<derfile::ModeRule>,
+    /// Should the written file get the mode of the template file?
+    pub preserve_mode: bool,
+    /// Path of the output file inside of the template directory it was found in, empty for
+    /// templates of single files. Example: `bin/backup.sh`
+    pub relative_path: String,
+    /// Whether the output is copied or linked to the apply path.
+    pub deploy: derfile::Deploy,
 }
 
 /// A template strucutre is either a template file or a template directory, which can then hold
@@ -61,9 +101,26 @@ pub struct TemplateDirectory {
     pub settings: TemplateSettings,
 }
 
+/// Mode and owner, which a written file should get. Unset parts are left as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilePermissions {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
 /// String ouput of a parsed template file.
 #[derive(Debug, Clone)]
-pub struct ParsedTemplate(String);
+pub struct ParsedTemplate(pub String);
+
+/// State shared by a template file and all the files it includes while rendering.
+struct Includes<'a> {
+    /// Paths of all template files, which are currently being rendered, the last one being the
+    /// innermost included file.
+    chain: Vec<path::PathBuf>,
+    /// Comment prefixes of the template, see `comment_prefixes`.
+    comments: &'a [String],
+}
 
 impl TemplateFile {
     /// Create a new instance of a `TemplateFile`.
@@ -97,13 +154,18 @@ impl TemplateFile {
         // still get included into the output file
 
         // Basic stuff.
-        let mut ret = String::new();
-        let hostname = execute_code("hostnamectl hostname")?;
-        if !self.0.hostnames.contains(&hostname) && debug() {
-            eprintln!(
-                "[\x1b[33mWARN\x1b[0m] $HOSTNAME not in hostnames for template file: {}",
-                self.0.path
-            )
+        let hostname = hostname()?;
+        if debug() {
+            match match_hostname(&self.0.hostnames, &hostname)? {
+                Some(pattern) => println!(
+                    "[\x1b[32mINFO\x1b[0m] Hostname {hostname} matched pattern {pattern} of template file: {}",
+                    self.0.path
+                ),
+                None => eprintln!(
+                    "[\x1b[33mWARN\x1b[0m] $HOSTNAME not in hostnames for template file: {}",
+                    self.0.path
+                ),
+            }
         }
         if !path::Path::new(&self.0.path).exists() {
             return Err("Error parsing template file: File does not exist1"
@@ -120,29 +182,11 @@ impl TemplateFile {
             file_lines = self.1.as_ref().unwrap().to_string()
         }
 
-        // Find all template code blocks.
-        let mut code_block_lines: Vec<(usize, String)> = Vec::new();
-        for (ii, line) in file_lines.lines().enumerate() {
-            if line.contains(TEMP_START) || line.contains(TEMP_END) {
-                code_block_lines.push((ii, line.to_string()));
-            }
-        }
-
-        // Check if all blocks are closed.
-        let open_code_blocks_count = code_block_lines
-            .iter()
-            .filter(|x| x.1.contains(TEMP_START))
-            .count();
-        let closed_code_blocks_count = code_block_lines
-            .iter()
-            .filter(|x| x.1.contains(TEMP_END))
-            .count();
-
-        if open_code_blocks_count != closed_code_blocks_count {
-            return Err("Error when parsing template file: Open template blocks don't match closed template blocks!".to_string().into());
-        }
-
-        if open_code_blocks_count == 0 {
+        let comments = comment_prefixes(&self.0.comments, &self.0.final_name);
+        let nodes = ast::parse(&file_lines, &comments).map_err(|e| e.in_file(&self.0.path))?;
+        if nodes.iter().all(|node| matches!(node, Node::Text(..)))
+            && !file_lines.contains(TEMP_VAR_START)
+        {
             if debug() {
                 eprintln!(
                     "[\x1b[33mWARN\x1b[0m] No code blocks were found in file {}",
@@ -152,116 +196,87 @@ impl TemplateFile {
             return Ok(ParsedTemplate(file_lines));
         }
 
-        let file_lines_vec = file_lines
-            .lines()
-            .map(ToString::to_string)
-            .collect::<Vec<String>>();
-
-        let mut parsed_code_blocks = Vec::new();
-        for chunk in code_block_lines.chunks(2) {
-            let code_block_first_line = &chunk[0].1;
-            let code_block_start_index = chunk[0].0;
-
-            let code_block_second_line = &chunk[1].1;
-            let code_block_end_index = chunk[1].0;
-
-            let parsed_first_line = code_block_first_line.clone();
-            let mut parsed_first_line = parsed_first_line.as_str();
-
-            while parsed_first_line.starts_with(' ') {
-                parsed_first_line = parsed_first_line.trim_start();
-            }
-
-            let possible_hostnames = parsed_first_line
-                .strip_prefix(TEMP_START)
-                .unwrap()
-                .split(',')
-                .into_iter()
-                .map(|x| x.trim())
-                .map(ToString::to_string)
-                .collect::<Vec<String>>()
-                .to_vec();
-
-            parsed_code_blocks.push((
-                code_block_first_line.clone(),
-                code_block_start_index,
-                code_block_second_line.clone(),
-                code_block_end_index,
-                possible_hostnames,
-            ))
-        }
+        let mut ret = String::new();
+        let facts = Facts {
+            hostname: &hostname,
+            vars: &self.0.vars,
+            groups: &self.0.groups,
+        };
+        let mut includes = Includes {
+            chain: vec![path::PathBuf::from(&self.0.path)],
+            comments: &self.0.comments,
+        };
+        render_nodes(&nodes, &facts, &mut includes, &mut ret)
+            .map_err(|e| e.in_file(&self.0.path))?;
 
-        // last line before code block
-        let mut last_ln = 0;
-        for (ii, each) in parsed_code_blocks.iter().enumerate() {
-            // begin line, start line number of code block, end line, end line number of
-            // code_block, hostnames for this code block
-            let mut good_codeblock = false;
-            let (start_line, start, end_line, end, hostnames) = each;
+        Ok(ParsedTemplate(ret))
+    }
 
-            if hostnames.contains(&hostname) {
-                good_codeblock = true;
-            }
+    /// Path, to which the parsed template file is written.
+    pub fn output_path(&self) -> path::PathBuf {
+        let mut output_path = path::PathBuf::from(&self.0.apply_path);
+        output_path.push(&self.0.final_name);
+        output_path
+    }
 
-            if good_codeblock {
-                ret.push_str(&file_lines_vec[last_ln..*start].join("\n"));
-                ret.push('\n');
-                let cloned_block = &file_lines_vec[*start..=*end];
-                let mut parsed_block = cloned_block
-                    .iter()
-                    .filter(|x| x != &start_line && x != &end_line)
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .to_vec();
-                let mut ready_block = parsed_block
-                    .iter_mut()
-                    .map(|x| {
-                        if x.is_empty() {
-                            String::from("\n")
-                        } else {
-                            x.to_string()
-                        }
-                    })
-                    .collect::<Vec<String>>()
-                    .to_vec();
-                if ready_block.len() == 1 {
-                    ready_block[0].push('\n');
-                }
-                ret.push_str(&ready_block.join("\n"));
-                last_ln = *end + 1;
+    /// Work out the mode and owner of the output file. The first of `mode_rules` matching the
+    /// file wins over `mode`, which wins over the mode of the template file with `preserve_mode`.
+    pub fn permissions(&self) -> Result<FilePermissions> {
+        let relative_path = if self.0.relative_path.is_empty() {
+            &self.0.final_name
+        } else {
+            &self.0.relative_path
+        };
+        let mut mode = None;
+        for (glob, rule_mode) in &self.0.mode_rules {
+            let name = if glob.contains('/') {
+                relative_path
             } else {
-                ret.push_str(&file_lines_vec[last_ln..*start].join("\n"));
-                ret.push('\n');
-                last_ln = *end + 1
-            }
-            if ii == parsed_code_blocks.len() - 1 {
-                ret.push_str(&file_lines_vec[*end + 1..].join("\n"));
+                &self.0.final_name
+            };
+            if Pattern::new(glob)?.is_match(name) {
+                mode = Some(*rule_mode);
+                break;
             }
         }
-        ret.push('\n');
+        let mode = match mode.or(self.0.mode) {
+            None if self.0.preserve_mode => Some(fs::metadata(&self.0.path)?.mode() & 0o7777),
+            mode => mode,
+        };
 
-        Ok(ParsedTemplate(ret))
+        Ok(FilePermissions {
+            mode,
+            uid: self.0.owner.as_deref().map(user_id).transpose()?,
+            gid: self.0.group.as_deref().map(group_id).transpose()?,
+        })
     }
 
-    /// Write a parsed template file to disk.
-    pub fn apply(&mut self) -> Result {
-        let parsed = self.parse()?;
-
-        if self.0.apply_path.ends_with('/') {
-            self.0.apply_path.push_str(&self.0.final_name);
-        } else {
-            self.0.apply_path.push('/');
-            self.0.apply_path.push_str(&self.0.final_name)
-        }
-        let output_path = path::Path::new(&self.0.apply_path);
+    /// Write a parsed template file to disk, unless the output file already has the same contents,
+    /// so its modification time only changes when it's actually changed. A new file gets `mode`
+    /// right away. Returns whether it was written.
+    pub fn write(&self, parsed: ParsedTemplate, mode: Option<u32>) -> Result<bool> {
+        let output_path = &self.output_path();
         if debug() {
             println!("[\x1b[32mINFO\x1b[0m] Outputting to: {output_path:#?}");
         }
-        if output_path.exists() {
-            fs::write(output_path, parsed.0).unwrap();
-        } else {
-            fs::create_dir_all(output_path.parent().expect("This shouldn't fail?")).unwrap();
-            fs::write(output_path, parsed.0).unwrap()
+
+        write_if_changed(output_path, parsed.0.as_bytes(), mode)
+    }
+}
+
+impl FilePermissions {
+    /// Give a written file its mode and owner, if it doesn't have them yet.
+    pub fn apply(&self, path: &path::Path) -> Result {
+        set_owner_and_mode(path, self.uid, self.gid, self.mode)?;
+        let metadata = fs::metadata(path)?;
+        if self.uid.is_some_and(|uid| uid != metadata.uid())
+            || self.gid.is_some_and(|gid| gid != metadata.gid())
+        {
+            return Err(format!(
+                "Unable to change the owner of {}, only root can give files away",
+                path.to_string_lossy()
+            )
+            .into());
         }
 
         Ok(())
@@ -288,6 +303,16 @@ impl From<derfile::Template> for TemplateSettings {
             extensions: other.extensions.clone(),
             parse_files: other.parse_files,
             recursive: other.recursive,
+            vars: HashMap::new(),
+            groups: HashMap::new(),
+            comments: other.comments.clone(),
+            mode: other.mode,
+            owner: other.owner.clone(),
+            group: other.group.clone(),
+            mode_rules: other.mode_rules.clone(),
+            preserve_mode: other.preserve_mode.unwrap_or(false),
+            relative_path: String::new(),
+            deploy: other.deploy.unwrap_or_default(),
             // keep_structure: other.keep_structure.clone(),
         }
     }
@@ -298,6 +323,16 @@ impl TemplateDirectory {
         Self { settings: ts }
     }
 
+    /// Whether the directory is linked as a whole, instead of its files one by one.
+    pub fn is_linked(&self) -> bool {
+        self.settings.deploy == derfile::Deploy::Symlink && !self.settings.parse_files
+    }
+
+    /// Path, to which the directory is output.
+    pub fn output_path(&self) -> path::PathBuf {
+        path::Path::new(&self.settings.apply_path).join(&self.settings.final_name)
+    }
+
     pub fn parse(&self) -> Result<Vec<TemplateStructure>> {
         let mut ret: Vec<TemplateStructure> = vec![];
         let current_dir_listed = list_dir(&self.settings.path)?;
@@ -316,6 +351,10 @@ impl TemplateDirectory {
             let mut apply_path_path = path::PathBuf::from(&cloned_settings.apply_path);
             apply_path_path.push(&self.settings.final_name);
             cloned_settings.apply_path = apply_path_path.to_str().unwrap().to_string();
+            cloned_settings.relative_path = path::Path::new(&self.settings.relative_path)
+                .join(&cloned_settings.final_name)
+                .to_string_lossy()
+                .to_string();
 
             if metadata.is_dir() {
                 if !cloned_settings.recursive {
@@ -342,17 +381,269 @@ impl TemplateDirectory {
     }
 }
 
-pub fn recursive_build(input: Vec<derfile::Template>) -> Result<TemplateStructures> {
+/// Select the comment prefixes, which apply to a file. Entries like `vim:"` only apply to files
+/// with the given extension, all other entries apply to every file.
+pub fn comment_prefixes<S: AsRef<str>>(comments: &[S], file_name: &str) -> Vec<String> {
+    let extensions: Vec<&str> = file_name.split('.').skip(1).collect();
+    comments
+        .iter()
+        .filter_map(|each| {
+            let each = each.as_ref();
+            match each.split_once(':') {
+                Some((extension, comment))
+                    if !extension.is_empty() && extension.chars().all(char::is_alphanumeric) =>
+                {
+                    extensions
+                        .contains(&extension)
+                        .then(|| comment.trim().to_string())
+                }
+                _ => Some(each.to_string()),
+            }
+        })
+        .filter(|each| !each.is_empty())
+        .collect()
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|x| x.trim().to_string())
+        .collect::<Vec<String>>()
+}
+
+impl ast::Condition {
+    fn matches(&self, facts: &Facts) -> Result<bool> {
+        let hostname = facts.hostname;
+        Ok(match self {
+            Self::Hostnames(hostnames) | Self::NotHostnames(hostnames) => {
+                let hostnames = derfile::expand_groups(hostnames, facts.groups)?;
+                let matched = match_hostname(&hostnames, hostname)?;
+                if let Some(pattern) = matched {
+                    if debug() {
+                        println!(
+                            "[\x1b[32mINFO\x1b[0m] Hostname {hostname} matched pattern: {pattern}"
+                        )
+                    }
+                }
+                matched.is_some() == matches!(self, Self::Hostnames(_))
+            }
+            Self::Expression(expr) => expr.eval(facts)?,
+            Self::Else => true,
+        })
+    }
+}
+
+/// Output all lines which should be output for the current host, with their original line endings.
+/// Contents of a branch, including all of its inner blocks, are only visited when the branch
+/// itself is selected, lines of block symbols and directives are never output.
+///
+/// Included files are resolved relative to the last file in the include chain, which is the file
+/// `nodes` belong to.
+fn render_nodes(
+    nodes: &[Node],
+    facts: &Facts,
+    includes: &mut Includes,
+    out: &mut String,
+) -> Result {
+    for node in nodes {
+        match node {
+            Node::Text(line) => {
+                out.push_str(&interpolate(line.text, *line, facts.vars)?);
+                out.push_str(line.ending);
+            }
+            Node::Escaped(line, before, after) => {
+                out.push_str(&interpolate(before, *line, facts.vars)?);
+                out.push_str(&interpolate(after, *line, facts.vars)?);
+                out.push_str(line.ending);
+            }
+            Node::Include(line, include_path) => {
+                let start = out.len();
+                render_include(include_path, *line, facts, includes, out)?;
+                // An included file without a final newline must not swallow the next line.
+                if out.len() > start && !out.ends_with('\n') {
+                    out.push_str(line.ending);
+                }
+            }
+            Node::Block(branches) => {
+                for branch in branches {
+                    let matches = branch.condition.matches(facts).map_err(|e| {
+                        branch
+                            .line
+                            .in_line(e, branch.header, "unable to evaluate block condition")
+                    })?;
+                    if matches {
+                        render_nodes(&branch.children, facts, includes, out)?;
+                        break;
+                    }
+                }
+            }
+            Node::Loop(each) => {
+                let values = match each.list.strip_prefix(derfile::VAR_PREF) {
+                    Some(name) => match facts.vars.get(name) {
+                        Some(variable) => variable.value.clone(),
+                        None => {
+                            return Err(each
+                                .line
+                                .error(each.list, format!("undefined variable `{name}`!")))
+                        }
+                    },
+                    None => split_list(each.list),
+                };
+
+                // Every iteration sees all the variables, with the loop variable added on top.
+                let mut vars = facts.vars.clone();
+                for value in values {
+                    vars.insert(
+                        each.name.to_string(),
+                        derfile::Variable::new(each.name.to_string(), vec![value]),
+                    );
+                    let facts = Facts {
+                        vars: &vars,
+                        ..*facts
+                    };
+                    render_nodes(&each.children, &facts, includes, out)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read, parse and render an included template file with the same facts as the file including it.
+fn render_include(
+    include_path: &str,
+    line: Line,
+    facts: &Facts,
+    includes: &mut Includes,
+    out: &mut String,
+) -> Result {
+    let include_path_part = include_path;
+    let include_path = interpolate(include_path, line, facts.vars)?;
+    let current = includes.chain.last().cloned().unwrap_or_default();
+    let mut full_path = current
+        .parent()
+        .map(path::Path::to_path_buf)
+        .unwrap_or_default();
+    full_path.push(&include_path);
+    let full_path = normalize_path(&full_path);
+
+    if includes.chain.contains(&full_path) {
+        let cycle = includes
+            .chain
+            .iter()
+            .skip_while(|each| *each != &full_path)
+            .chain([&full_path])
+            .map(|each| each.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        return Err(line.error(include_path_part, format!("include cycle: {cycle}")));
+    }
+
+    let contents = fs::read_to_string(&full_path).map_err(|e| {
+        line.error(
+            include_path_part,
+            format!(
+                "unable to include {}: {:?}",
+                full_path.to_string_lossy(),
+                e.kind()
+            ),
+        )
+    })?;
+
+    let comments = comment_prefixes(includes.comments, &include_path);
+    includes.chain.push(full_path);
+    let result = ast::parse(&contents, &comments)
+        .and_then(|nodes| render_nodes(&nodes, facts, includes, out));
+    let full_path = includes.chain.pop().unwrap_or_default();
+
+    // Errors from within the included file get the include chain appended, one line per file.
+    result.map_err(|e| match e.in_file(&full_path.to_string_lossy()) {
+        Error::Diagnostic(mut diagnostic) => {
+            diagnostic.notes.push(format!(
+                "included from {}:{}",
+                current.to_string_lossy(),
+                line.number
+            ));
+            Error::Diagnostic(diagnostic)
+        }
+        Error::Custom(message) => format!(
+            "{message}\n    in {}, included from {}:{}",
+            full_path.to_string_lossy(),
+            current.to_string_lossy(),
+            line.number
+        )
+        .into(),
+        other => other,
+    })
+}
+
+/// Replace all variable references in `text`, a part of `line`, with the values of the variables.
+/// Values of list variables are joined by commas. A reference prefixed with the escape symbol is
+/// output literally, without the escape symbol.
+fn interpolate(
+    text: &str,
+    line: Line,
+    vars: &HashMap<String, derfile::Variable>,
+) -> Result<String> {
+    let mut ret = String::new();
+    let mut rest = text;
+
+    while let Some(index) = rest.find(TEMP_VAR_START) {
+        let (before, reference) = rest.split_at(index);
+
+        if let Some(before) = before.strip_suffix(TEMP_ESCAPE) {
+            ret.push_str(before);
+            ret.push_str(TEMP_VAR_START);
+            rest = &reference[TEMP_VAR_START.len()..];
+            continue;
+        }
+        ret.push_str(before);
+
+        let reference = &reference[TEMP_VAR_START.len()..];
+        let Some(end) = reference.find(TEMP_VAR_END) else {
+            return Err(line.error(
+                &rest[index..],
+                format!("variable reference is missing a closing `{TEMP_VAR_END}`!"),
+            ));
+        };
+        let name = reference[..end].trim();
+        match vars.get(name) {
+            Some(variable) => ret.push_str(&variable.value.join(",")),
+            None => {
+                return Err(line.error(
+                    &rest[index..index + TEMP_VAR_START.len() + end + TEMP_VAR_END.len()],
+                    format!("undefined variable `{name}`!"),
+                ))
+            }
+        }
+        rest = &reference[end + TEMP_VAR_END.len()..];
+    }
+    ret.push_str(rest);
+
+    Ok(ret)
+}
+
+pub fn recursive_build(
+    input: Vec<derfile::Template>,
+    vars: &HashMap<String, derfile::Variable>,
+    groups: &derfile::Groups,
+) -> Result<TemplateStructures> {
     let mut ret: TemplateStructures = Vec::new();
     for template in input.into_iter() {
         if path::Path::new(&template.name).is_dir() {
-            let settings: TemplateSettings = template.into();
+            let mut settings: TemplateSettings = template.into();
+            settings.vars = vars.clone();
+            settings.groups = groups.clone();
             let dir = TemplateDirectory::new(settings);
 
             ret.push(TemplateStructure::Directory(dir.clone()));
-            ret.append(&mut dir.parse()?)
+            if !dir.is_linked() {
+                ret.append(&mut dir.parse()?)
+            }
         } else if path::Path::new(&template.name).is_file() {
-            let settings: TemplateSettings = template.into();
+            let mut settings: TemplateSettings = template.into();
+            settings.vars = vars.clone();
+            settings.groups = groups.clone();
             let file: TemplateFile = TemplateFile::new(settings, None);
 
             ret.push(TemplateStructure::File(file));
@@ -372,7 +663,13 @@ mod test {
     use super::derfile::Derfile;
     use super::execute_code;
     use super::TemplateFile;
+    use super::{comment_prefixes, render_nodes, Includes};
+    use crate::ast::parse as parse_nodes;
+    use crate::condition::Facts;
     use crate::config::Config;
+    use crate::derfile::Variable;
+    use crate::error::Error;
+    use std::collections::HashMap;
     use std::path::Path;
 
     #[test]
@@ -405,7 +702,7 @@ hostnames = $host
             .unwrap()
             .1;
         let mut template_file = TemplateFile::new(template.clone().into(), Some(template_string));
-        assert_eq!(template_file.parse().is_ok(), true);
+        assert!(template_file.parse().is_ok());
         let output = template_file.parse().unwrap().0;
         println!("{}", output);
         assert_eq!(
@@ -413,4 +710,411 @@ hostnames = $host
             "some stuff\nmore stuff\nand even more stuff\n".to_string()
         )
     }
+
+    fn render(contents: &str, hostname: &str) -> String {
+        render_with_vars(contents, hostname, &HashMap::new()).unwrap()
+    }
+
+    fn render_with_vars(
+        contents: &str,
+        hostname: &str,
+        vars: &HashMap<String, Variable>,
+    ) -> crate::error::Result<String> {
+        let facts = Facts {
+            hostname,
+            vars,
+            groups: &HashMap::new(),
+        };
+        render_with_facts(contents, &facts)
+    }
+
+    /// Line number a diagnostic error points to.
+    fn line_of(error: &Error) -> usize {
+        match error {
+            Error::Diagnostic(diagnostic) => diagnostic.line,
+            other => panic!("expected a diagnostic, got: {other}"),
+        }
+    }
+
+    fn render_with_facts(contents: &str, facts: &Facts) -> crate::error::Result<String> {
+        render_with_comments(contents, facts, &[])
+    }
+
+    fn render_with_comments(
+        contents: &str,
+        facts: &Facts,
+        comments: &[String],
+    ) -> crate::error::Result<String> {
+        let nodes = parse_nodes(contents, comments)?;
+        let mut out = String::new();
+        let mut includes = Includes {
+            chain: vec![],
+            comments,
+        };
+        render_nodes(&nodes, facts, &mut includes, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let template_string = "a\n@@ laptop, desktop\nb\n@@ laptop\nc\n@!\nd\n@!\ne";
+
+        assert_eq!(render(template_string, "laptop"), "a\nb\nc\nd\ne");
+        assert_eq!(render(template_string, "desktop"), "a\nb\nd\ne");
+        assert_eq!(render(template_string, "server"), "a\ne");
+    }
+
+    #[test]
+    fn test_inner_block_needs_active_outer_block() {
+        let template_string = "@@ desktop\n@@ laptop\ninner\n@!\n@!\nlast";
+
+        assert_eq!(render(template_string, "laptop"), "last");
+    }
+
+    #[test]
+    fn test_unbalanced_blocks() {
+        let unclosed = parse_nodes("a\n@@ laptop\n@@ desktop\nb\n@!\n", &[]).unwrap_err();
+        assert_eq!(line_of(&unclosed), 2);
+
+        let unopened = parse_nodes("a\n@@ laptop\nb\n@!\n@!\n", &[]).unwrap_err();
+        assert_eq!(line_of(&unopened), 5);
+    }
+
+    #[test]
+    fn test_negated_blocks() {
+        let template_string = "@@ !laptop, desktop\nserver\n@!";
+
+        assert_eq!(render(template_string, "server"), "server\n");
+        assert_eq!(render(template_string, "laptop"), "");
+        assert_eq!(render(template_string, "desktop"), "");
+    }
+
+    #[test]
+    fn test_else_branches() {
+        let template_string =
+            "@@ desktop\nfont = 14\n@: laptop\nfont = 10\n@: !server\nfont = 12\n@:\nno font\n@!";
+
+        assert_eq!(render(template_string, "desktop"), "font = 14\n");
+        assert_eq!(render(template_string, "laptop"), "font = 10\n");
+        assert_eq!(render(template_string, "tablet"), "font = 12\n");
+        assert_eq!(render(template_string, "server"), "no font\n");
+    }
+
+    #[test]
+    fn test_misplaced_else_branches() {
+        assert!(parse_nodes("a\n@:\nb\n", &[]).is_err());
+        assert!(parse_nodes("@@ a\n@:\nb\n@: c\n@!\n", &[]).is_err());
+    }
+
+    #[test]
+    fn test_variable_interpolation() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "font_size".to_string(),
+            Variable::new("font_size".to_string(), vec!["12".to_string()]),
+        );
+        vars.insert(
+            "hosts".to_string(),
+            Variable::new("hosts".to_string(), vec!["a".to_string(), "b".to_string()]),
+        );
+        let template_string =
+            "size = @{font_size}\n@@ laptop\nhosts = @{ hosts }\n@!\nliteral = \\@{font_size}";
+
+        assert_eq!(
+            render_with_vars(template_string, "laptop", &vars).unwrap(),
+            "size = 12\nhosts = a,b\nliteral = @{font_size}"
+        );
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let error = render_with_vars("a\nb = @{nope}", "laptop", &HashMap::new()).unwrap_err();
+        assert_eq!(line_of(&error), 2);
+        assert!(error.to_string().contains("nope"));
+
+        // Undefined variables in blocks, which are not output, are not an error.
+        assert!(render_with_vars("@@ desktop\n@{nope}\n@!", "laptop", &HashMap::new()).is_ok());
+        assert!(render_with_vars("@{unterminated", "laptop", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_condition_blocks() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "theme".to_string(),
+            Variable::new("theme".to_string(), vec!["dark".to_string()]),
+        );
+        let template_string =
+            "@@ $theme == dark and hostname != server\ndark\n@: laptop or desktop\nlight\n@!";
+
+        assert_eq!(
+            render_with_vars(template_string, "laptop", &vars).unwrap(),
+            "dark\n"
+        );
+        assert_eq!(
+            render_with_vars(template_string, "server", &vars).unwrap(),
+            ""
+        );
+
+        let error = parse_nodes("a\n@@ $theme ==\n@!", &[]).unwrap_err();
+        assert_eq!(line_of(&error), 2);
+        let error = render_with_vars("a\n@@ $nope == a\n@!", "laptop", &vars).unwrap_err();
+        assert_eq!(line_of(&error), 2);
+    }
+
+    #[test]
+    fn test_includes() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "shell".to_string(),
+            Variable::new("shell".to_string(), vec!["zsh".to_string()]),
+        );
+        let template_string = "start\n@@include test/include/aliases.t\n@@ desktop\n@@include test/include/missing.t\n@!\nend";
+
+        assert_eq!(
+            render_with_vars(template_string, "laptop", &vars).unwrap(),
+            "start\nalias ls='ls --color'\nalias vim=nvim\nexport SHELL=zsh\nend"
+        );
+        assert!(render_with_vars(template_string, "desktop", &vars).is_err());
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let error = render_with_vars(
+            "@@include test/include/cycle_a.t",
+            "laptop",
+            &HashMap::new(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("include cycle"));
+        assert!(error.contains("cycle_a.t -> test/include/cycle_b.t -> test/include/cycle_a.t"));
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut vars = HashMap::new();
+        vars.insert(
+            "hosts".to_string(),
+            Variable::new(
+                "hosts".to_string(),
+                vec!["build-01".to_string(), "laptop".to_string()],
+            ),
+        );
+        let template_string = "@@for host in $hosts\nHost @{host}\n@@ $host != laptop\n    User builder\n@!\n@@end\n@@for ws in 1, 2\nbindsym $mod+@{ws} workspace @{ws}\n@@end";
+
+        assert_eq!(
+            render_with_vars(template_string, "laptop", &vars).unwrap(),
+            "Host build-01\n    User builder\nHost laptop\nbindsym $mod+1 workspace 1\nbindsym $mod+2 workspace 2\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_loops() {
+        assert!(parse_nodes("@@for host\n@@end", &[]).is_err());
+        assert!(parse_nodes("@@for host in $hosts\n", &[]).is_err());
+        assert!(parse_nodes("@@for host in $hosts\n@!", &[]).is_err());
+        assert!(parse_nodes("@@ laptop\n@@end", &[]).is_err());
+        assert!(render_with_vars("@@for a in $nope\n@@end", "laptop", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_hostname_patterns() {
+        let template_string =
+            "@@ desktop, *-laptop\na\n@: re:^build-\\d{2}$\nb\n@!\n@@ !re:^build\nc\n@!";
+
+        assert_eq!(render(template_string, "work-laptop"), "a\nc\n");
+        assert_eq!(render(template_string, "build-01"), "b\n");
+        assert_eq!(render(template_string, "build-123"), "");
+        assert!(parse_nodes("@@ re:(a\n@!", &[]).is_ok());
+        assert!(render_with_vars("@@ re:(a\n@!", "a", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_host_groups() {
+        let mut groups = HashMap::new();
+        groups.insert(
+            "gui".to_string(),
+            vec!["desktop".to_string(), "%laptops".to_string()],
+        );
+        groups.insert("laptops".to_string(), vec!["*-laptop".to_string()]);
+        let template_string = "@@ %gui\ngui\n@@ %laptops\nlaptop\n@!\n@: server\nserver\n@!";
+        let render = |hostname| {
+            let facts = Facts {
+                hostname,
+                vars: &HashMap::new(),
+                groups: &groups,
+            };
+            render_with_facts(template_string, &facts)
+        };
+
+        assert_eq!(render("work-laptop").unwrap(), "gui\nlaptop\n");
+        assert_eq!(render("desktop").unwrap(), "gui\n");
+        assert_eq!(render("server").unwrap(), "server\n");
+        assert!(render_with_vars("@@ %nope\n@!", "a", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_comment_prefixes() {
+        let comments = vec!["#".to_string(), "vim:\"".to_string(), "lua:--".to_string()];
+
+        assert_eq!(comment_prefixes(&comments, "init.vim"), vec!["#", "\""]);
+        assert_eq!(comment_prefixes(&comments, "init.lua.t"), vec!["#", "--"]);
+        assert_eq!(comment_prefixes(&comments, "bashrc"), vec!["#"]);
+    }
+
+    #[test]
+    fn test_commented_markers() {
+        let facts = Facts {
+            hostname: "laptop",
+            vars: &HashMap::new(),
+            groups: &HashMap::new(),
+        };
+        let template_string =
+            "set nu\n  \" @@ laptop\nset rnu\n\" @: desktop\nset nornu\n\" @!\n\" a normal comment";
+        let comments = vec!["\"".to_string()];
+
+        assert_eq!(
+            render_with_comments(template_string, &facts, &comments).unwrap(),
+            "set nu\nset rnu\n\" a normal comment"
+        );
+        // Without the comment prefixes, the symbols are just text.
+        assert_eq!(
+            render_with_comments(template_string, &facts, &[]).unwrap(),
+            template_string
+        );
+    }
+
+    #[test]
+    fn test_markers_are_whole_lines() {
+        assert!(parse_nodes("@@ laptop\n@! trailing text\n", &[]).is_err());
+        assert_eq!(render("@@ laptop\na\n  @!  \nb", "laptop"), "a\nb");
+    }
+
+    #[test]
+    fn test_escaped_symbols() {
+        let template_string = "\\@@ laptop\n  \\@!\n\\@@include a.t\n\\\\@: b\n\\@{x} \\@@";
+        assert_eq!(
+            render(template_string, "laptop"),
+            "@@ laptop\n  @!\n@@include a.t\n\\@: b\n@{x} \\@@"
+        );
+
+        let facts = Facts {
+            hostname: "laptop",
+            vars: &HashMap::new(),
+            groups: &HashMap::new(),
+        };
+        assert_eq!(
+            render_with_comments("# \\@@ laptop", &facts, &["#".to_string()]).unwrap(),
+            "# @@ laptop"
+        );
+    }
+
+    #[test]
+    fn test_scss_is_not_a_directive() {
+        let template_string = ".btn {\n  @include flex-center;\n}\n@for $i from 1 through 3 {\n  .m-#{$i} { margin: $i; }\n}\n";
+        assert_eq!(render(template_string, "laptop"), template_string);
+    }
+
+    #[test]
+    fn test_objective_c_is_not_a_directive() {
+        let template_string = "@interface Foo : NSObject\n@end\n";
+        assert_eq!(render(template_string, "laptop"), template_string);
+    }
+
+    #[test]
+    fn test_text_is_not_a_marker() {
+        let template_string =
+            "@@ -1,3 +1,4 @@ fn main() {\n@@@ text\n@:@ text\nuser@@example.com\n@!important";
+        assert_eq!(render(template_string, "laptop"), template_string);
+        let template_string = "@@include a.t @@ b.t\n@@for x in a @@\n@@end @@\n@@@end\n@@@include a.t\n@include a.t\n@for x in a\n@end";
+        assert_eq!(render(template_string, "laptop"), template_string);
+        assert!(parse_nodes("@@\n@!", &[]).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let error = parse_nodes("a\n  @: laptop\n", &[]).unwrap_err();
+        let Error::Diagnostic(diagnostic) = error.in_file("some/file.t") else {
+            panic!("expected a diagnostic");
+        };
+        assert_eq!(diagnostic.path, "some/file.t");
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(diagnostic.columns, 3..5);
+        assert_eq!(diagnostic.snippet, "  @: laptop");
+
+        let error = render_with_vars("a = @{nope} @{x}", "laptop", &HashMap::new()).unwrap_err();
+        let Error::Diagnostic(diagnostic) = error else {
+            panic!("expected a diagnostic");
+        };
+        assert_eq!(diagnostic.columns, 5..12);
+        let rendered = diagnostic.to_string();
+        assert!(rendered.contains("undefined variable `nope`"));
+        assert!(rendered.contains("--> :1:5"));
+        assert!(rendered.contains("1 | a = @{nope} @{x}"));
+        assert!(rendered.contains("  |     \x1b[31m^^^^^^^\x1b[0m"));
+    }
+
+    #[test]
+    fn test_byte_exact_rendering() {
+        let template_string = "a  \r\n@@ desktop\r\nb\r\n@!\r\n\r\n@@ laptop\r\nc\t\r\n@!\r\nd";
+        assert_eq!(render(template_string, "laptop"), "a  \r\n\r\nc\t\r\nd");
+        assert_eq!(render(template_string, "desktop"), "a  \r\nb\r\n\r\nd");
+
+        assert_eq!(render("@@ laptop\na\n@!", "laptop"), "a\n");
+        assert_eq!(render("@@ laptop\na\n@!\n", "desktop"), "");
+        assert_eq!(render("a\n@@ laptop\n\n@!\nb\n", "laptop"), "a\n\nb\n");
+    }
+
+    #[test]
+    fn test_permissions() {
+        use super::{recursive_build, TemplateStructure};
+        use crate::derfile::Template;
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::utils::TempDir::new("mode");
+        fs::create_dir_all(dir.join("bin/secret")).unwrap();
+        fs::write(dir.join("bin/run.sh.t"), "").unwrap();
+        fs::write(dir.join("bin/tool"), "").unwrap();
+        fs::set_permissions(dir.join("bin/tool"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(dir.join("bin/secret/key.sh"), "").unwrap();
+
+        let mut template = Template {
+            name: dir.join("bin").to_string_lossy().to_string(),
+            final_name: "bin".to_string(),
+            apply_path: dir.join("out").to_string_lossy().to_string(),
+            recursive: true,
+            ..Default::default()
+        };
+        template.add_extension("t".to_string());
+        template.add_mode_rule(("secret/*".to_string(), 0o600));
+        template.add_mode_rule(("*.sh".to_string(), 0o755));
+        template.set_preserve_mode(true);
+        template.set_owner("root".to_string());
+
+        let mut modes: Vec<(String, Option<u32>)> =
+            recursive_build(vec![template], &HashMap::new(), &HashMap::new())
+                .unwrap()
+                .into_iter()
+                .filter_map(|structure| match structure {
+                    TemplateStructure::File(f) => {
+                        let permissions = f.permissions().unwrap();
+                        assert_eq!(permissions.uid, Some(0));
+                        Some((f.0.relative_path, permissions.mode))
+                    }
+                    _ => None,
+                })
+                .collect();
+        modes.sort();
+
+        assert_eq!(
+            modes,
+            vec![
+                ("run.sh".to_string(), Some(0o755)),
+                ("secret/key.sh".to_string(), Some(0o600)),
+                ("tool".to_string(), Some(0o750)),
+            ]
+        );
+    }
 }
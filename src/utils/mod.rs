@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
 use std::path::{Component, Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output};
 
 use crate::error::*;
 use crate::DEBUG;
@@ -28,6 +30,23 @@ pub fn normalize_path<P: AsRef<Path> + ?Sized>(path: &P) -> PathBuf {
     ret
 }
 
+/// Path of a file inside of a directory, which mirrors the whole file system, e.g.
+/// `/home/user/.bashrc` is `<root>/home/user/.bashrc`.
+pub fn mirror_path(root: &Path, path: &Path) -> PathBuf {
+    let path = normalize_path(path);
+    root.join(path.strip_prefix("/").unwrap_or(&path))
+}
+
+/// Ask a question on the terminal and return the trimmed answer.
+pub fn ask(question: &str) -> Result<String> {
+    print!("{question} ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(answer.trim().to_string())
+}
+
 #[allow(unused)]
 pub fn visit_directories<P: AsRef<Path>>(path: &P) -> Result<Vec<(PathBuf, fs::Metadata)>> {
     let mut ret: Vec<(PathBuf, fs::Metadata)> = Vec::new();
@@ -69,7 +88,7 @@ pub fn remove_template_ext_or_dir<S: AsRef<str>, P: AsRef<Path>>(
     needles: &[S],
 ) -> String {
     let path = haystack.as_ref().to_path_buf();
-    let final_component = path.components().last().unwrap();
+    let final_component = path.components().next_back().unwrap();
     let final_component_string = final_component.as_os_str().to_str().unwrap().to_string();
 
     if path.is_dir() {
@@ -89,13 +108,207 @@ pub fn remove_template_ext_or_dir<S: AsRef<str>, P: AsRef<Path>>(
     final_component_string
 }
 
+/// Suffix of temporary files, which are renamed over their target by `write_atomic`.
+pub const TEMP_FILE_SUFFIX: &str = ".der-tmp";
+
+/// Path of the temporary file used to write `path`, which is `.<name>.der-tmp` next to it.
+pub fn temp_file_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}{TEMP_FILE_SUFFIX}"))
+}
+
+/// Remove the temporary file of `path`, which an aborted run left behind. Returns whether there
+/// was one.
+pub fn remove_stale_temp_file(path: &Path) -> Result<bool> {
+    let temp = temp_file_path(path);
+    if fs::symlink_metadata(&temp).is_err() {
+        return Ok(false);
+    }
+    fs::remove_file(&temp)?;
+    if debug() {
+        println!(
+            "[\x1b[32mINFO\x1b[0m] Removed stale temporary file: {}",
+            temp.to_string_lossy()
+        );
+    }
+
+    Ok(true)
+}
+
+/// Write a file, so that it either has its old or its new contents, even if der is interrupted,
+/// crashes or the disk is full.
+///
+/// The contents are written to a temporary file in the same directory, synced to disk and then
+/// renamed over the target. An existing target keeps its owner, if possible, and its permissions,
+/// unless `mode` is given. The mode is set before the rename, so the contents are never readable
+/// with looser permissions than the file gets in the end. Symlinks are followed, so the file they
+/// point to is replaced, not the link. A temporary file left behind by an aborted run is removed
+/// first.
+pub fn write_atomic(path: &Path, contents: &[u8], mode: Option<u32>) -> Result {
+    let path = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+
+    let temp = temp_file_path(&path);
+    remove_stale_temp_file(&path)?;
+
+    let result = (|| -> Result {
+        let mut file = fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        let existing = fs::metadata(&path).ok();
+        set_owner_and_mode(
+            &temp,
+            existing.as_ref().map(|metadata| metadata.uid()),
+            existing.as_ref().map(|metadata| metadata.gid()),
+            mode.or(existing.as_ref().map(|metadata| metadata.mode())),
+        )?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        // Make the rename itself durable.
+        fs::File::open(parent)?.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
+}
+
+/// Give a file an owner, a group and a mode, each one only if it's given and the file doesn't
+/// have it yet. Returns whether anything was changed.
+///
+/// Changing the owner clears the setuid and setgid bits, so it has to come before the mode. Only
+/// root can give files away, so changing the owner fails for everyone else. That's fine for giving
+/// files back to their previous owner, callers, which need the owner, have to check it.
+pub fn set_owner_and_mode(
+    path: &Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+) -> Result<bool> {
+    let metadata = fs::metadata(path)?;
+    let mut changed = false;
+    if uid.is_some_and(|uid| uid != metadata.uid()) || gid.is_some_and(|gid| gid != metadata.gid())
+    {
+        changed = chown(path, uid, gid).is_ok();
+    }
+    if let Some(mode) = mode {
+        if fs::metadata(path)?.mode() & 0o7777 != mode & 0o7777 {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            changed = true;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Write a file atomically, unless it already has the contents. Returns whether it was written.
+pub fn write_if_changed(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<bool> {
+    if fs::read(path).is_ok_and(|current| current == contents) {
+        return Ok(false);
+    }
+    write_atomic(path, contents, mode)?;
+
+    Ok(true)
+}
+
+/// Parse an octal file mode, like `755`, `0644` or `0o600`.
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    let mode = mode.trim();
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| !digits.is_empty() && *mode <= 0o7777)
+}
+
+/// Find the id of a user, which can be given by its name or its id.
+pub fn user_id(user: &str) -> Result<u32> {
+    lookup_id("/etc/passwd", user).ok_or_else(|| format!("Unknown user: {user}").into())
+}
+
+/// Find the id of a group, which can be given by its name or its id.
+pub fn group_id(group: &str) -> Result<u32> {
+    lookup_id("/etc/group", group).ok_or_else(|| format!("Unknown group: {group}").into())
+}
+
+/// Look up a name in a file like `/etc/passwd`, whose lines look like `name:password:id:...`.
+fn lookup_id(file: &str, name: &str) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+
+    fs::read_to_string(file).ok()?.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next()? == name).then_some(())?;
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// Put a symlink to `source` at `path`, replacing a file or a link, which is already there, in a
+/// single step.
+pub fn symlink_atomic(source: &Path, path: &Path) -> Result {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = temp_file_path(path);
+    remove_stale_temp_file(path)?;
+    std::os::unix::fs::symlink(source, &temp)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Whether a path is a symlink, without following it.
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
 pub fn debug() -> bool {
     DEBUG.with(|v| *v.borrow())
 }
 
+/// Directory, in which der keeps its state, like backups. This is `$XDG_STATE_HOME/der`, or
+/// `~/.local/state/der`.
+pub fn state_dir() -> Result<PathBuf> {
+    let state_home = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME")?).join(".local/state"),
+    };
+
+    Ok(state_home.join("der"))
+}
+
+/// Environmental variable, which overrides the hostname of the current machine. It is also set by
+/// the `--host` flag, so shell code in derfiles can see it too.
+pub const HOSTNAME_VAR: &str = "DER_HOSTNAME";
+
+/// Get the hostname, for which templates should be rendered. This is the hostname of the current
+/// machine, unless it's overridden with `DER_HOSTNAME`.
+pub fn hostname() -> Result<String> {
+    match env::var(HOSTNAME_VAR) {
+        Ok(hostname) if !hostname.trim().is_empty() => Ok(hostname.trim().to_string()),
+        _ => execute_code("hostnamectl hostname"),
+    }
+}
+
 pub fn execute_code<S: AsRef<str>>(command: S) -> Result<String> {
-    // Get a list of all environmental variables.
-    let vars: HashMap<String, String> = env::vars().collect();
     // Split the command into its components.
     let command = command.as_ref();
     if command.contains(' ') {
@@ -103,27 +316,88 @@ pub fn execute_code<S: AsRef<str>>(command: S) -> Result<String> {
         let cmd = split[0];
         let args = &split[1..];
 
-        let output = Command::new(cmd).args(args).envs(&vars).output()?;
-
-        let str = std::str::from_utf8(&output.stdout);
-        let str = str.unwrap_or_else(|_| {
-            panic!(
-                "ERROR: Unable to convert command output to string! [Error value: {}]",
-                &command
-            )
-        });
-        return Ok(str.trim().to_string());
+        let output = run_command(Command::new(cmd).args(args), &[])?;
+        Ok(output_string(&output.stdout))
     } else {
-        let output = Command::new(command).envs(&vars).output()?;
+        let output = run_command(&mut Command::new(command), &[])?;
+        Ok(output_string(&output.stdout))
+    }
+}
+
+/// Run the command of a hook through `sh`, so it can use pipes, globs and variables, with some
+/// extra environmental variables. Unlike with `execute_code`, a non-zero exit status is an error.
+pub fn execute_hook(command: &str, vars: &[(&str, String)]) -> Result<String> {
+    let output = run_command(Command::new("sh").arg("-c").arg(command), vars)?;
+    if !output.status.success() {
+        let stderr = output_string(&output.stderr);
+        return Err(format!(
+            "Hook failed with {}: {command}{}",
+            output.status,
+            match stderr.as_str() {
+                "" => String::new(),
+                stderr => format!("\n{stderr}"),
+            }
+        )
+        .into());
+    }
+
+    Ok(output_string(&output.stdout))
+}
+
+/// Run a command with all environmental variables, and some extra ones, and wait for its output.
+fn run_command(command: &mut Command, extra_vars: &[(&str, String)]) -> Result<Output> {
+    // Get a list of all environmental variables.
+    let vars: HashMap<String, String> = env::vars().collect();
+    Ok(command
+        .envs(&vars)
+        .envs(extra_vars.iter().map(|(name, value)| (name, value)))
+        .output()?)
+}
+
+/// Output of a command as a string. Commands can print anything, so invalid UTF-8 is replaced
+/// instead of being an error.
+fn output_string(output: &[u8]) -> String {
+    String::from_utf8_lossy(output).trim().to_string()
+}
+
+/// A fresh directory for a test, which is removed again when it goes out of scope, even if the
+/// test fails.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("der-{name}-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        TempDir(dir)
+    }
+
+    /// Create files in the directory, each one containing its own name, and return their paths.
+    pub fn files<const N: usize>(&self, names: [&str; N]) -> [PathBuf; N] {
+        names.map(|name| {
+            let path = self.join(name);
+            fs::write(&path, name).unwrap();
+            path
+        })
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
 
-        let str = std::str::from_utf8(&output.stdout);
-        let str = str.unwrap_or_else(|_| {
-            panic!(
-                "ERROR: Unable to convert command output to string! [Error value: {}]",
-                &command
-            )
-        });
-        return Ok(str.trim().to_string());
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
     }
 }
 
@@ -145,11 +419,93 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_write_atomic() {
+        use std::fs;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = super::TempDir::new("write");
+        let target = dir.join("sub/config");
+        super::write_atomic(&target, b"one", None).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "one");
+
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+        let temp = super::temp_file_path(&target);
+        assert_eq!(temp, dir.join("sub/.config.der-tmp"));
+        fs::write(&temp, "stale").unwrap();
+        super::write_atomic(&target, b"two", None).unwrap();
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "two");
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!temp.exists());
+
+        // Writing the same contents again doesn't touch the file.
+        let inode = fs::metadata(&target).unwrap().ino();
+        assert!(!super::write_if_changed(&target, b"two", None).unwrap());
+        assert_eq!(fs::metadata(&target).unwrap().ino(), inode);
+        assert!(super::write_if_changed(&target, b"three", None).unwrap());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "three");
+
+        // A given mode is already set on the temporary file.
+        let secret = dir.join("secret");
+        super::write_atomic(&secret, b"secret", Some(0o600)).unwrap();
+        assert_eq!(
+            fs::metadata(&secret).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(super::write_if_changed(&target, b"four", Some(0o640)).unwrap());
+        assert_eq!(
+            fs::metadata(&target).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+    }
+
+    #[test]
+    fn test_modes_and_ids() {
+        assert_eq!(super::parse_mode("755"), Some(0o755));
+        assert_eq!(super::parse_mode("0o600"), Some(0o600));
+        assert_eq!(super::parse_mode("04755"), Some(0o4755));
+        assert_eq!(super::parse_mode("rwx"), None);
+        assert_eq!(super::parse_mode("778"), None);
+        assert_eq!(super::parse_mode("17777"), None);
+
+        assert_eq!(super::user_id("root").unwrap(), 0);
+        assert_eq!(super::user_id("1234").unwrap(), 1234);
+        assert_eq!(super::group_id("root").unwrap(), 0);
+        assert!(super::user_id("no-such-user").is_err());
+    }
+
     #[test]
     fn test_execute_code() {
         assert!(super::execute_code("hostnamectl hostname").is_ok())
     }
 
+    #[test]
+    fn test_hooks() {
+        let vars = [("DER_TARGET", "/tmp/target".to_string())];
+        assert_eq!(
+            super::execute_hook("echo $DER_TARGET | tr a-z A-Z", &vars).unwrap(),
+            "/TMP/TARGET"
+        );
+        let error = super::execute_hook("echo oops >&2; exit 3", &vars).unwrap_err();
+        assert!(error.to_string().contains("exit status: 3"));
+        assert!(error.to_string().contains("oops"));
+        let error = super::execute_hook("printf '\\377' >&2; false", &vars).unwrap_err();
+        assert!(error.to_string().contains('\u{FFFD}'));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        assert_eq!(super::execute_code("printf \\377").unwrap(), "\u{FFFD}");
+        assert_eq!(
+            super::execute_hook("printf '\\377'", &[]).unwrap(),
+            "\u{FFFD}"
+        );
+    }
+
     #[test]
     fn test_hostname() {
         assert_eq!(
@@ -0,0 +1,5 @@
+alias ls='ls --color'
+@@ laptop
+alias vim=nvim
+@!
+@@include path/@{shell}.t